@@ -19,21 +19,23 @@ transform = transforms.Compose([
     transforms.ToTensor(),
 ])
 
-def classify(image_path):
-    image = Image.open(image_path).convert("RGB")
-    input_tensor = transform(image).unsqueeze(0).to(device)  # Add batch dimension
+def top_k(image, k):
+    """The k most likely labels for a PIL image, as (label, confidence) pairs"""
+    input_tensor = transform(image.convert("RGB")).unsqueeze(0).to(device)  # Add batch dimension
     with torch.no_grad():
         output = model(input_tensor)
         probs = torch.nn.functional.softmax(output[0], dim=0)
-        top5 = torch.topk(probs, 5)
-        for i in range(5):
-            label = labels[top5.indices[i]]
-            score = top5.values[i].item()
-            print(f"{label}: {score:.2%}")
+        top = torch.topk(probs, min(k, len(labels)))
+        return [(labels[index], score.item()) for score, index in zip(top.values, top.indices)]
+
+def classify(image_path):
+    for label, score in top_k(Image.open(image_path), 5):
+        print(f"{label}: {score:.2%}")
 
 # --- Run
-import sys
-if len(sys.argv) != 2:
-    print("Usage: python3 infer.py image.jpg")
-else:
-    classify(sys.argv[1])
+if __name__ == "__main__":
+    import sys
+    if len(sys.argv) != 2:
+        print("Usage: python3 infer.py image.jpg")
+    else:
+        classify(sys.argv[1])
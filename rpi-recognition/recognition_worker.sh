@@ -0,0 +1,3 @@
+#!/bin/bash
+
+exec ./venv/bin/python3 -u worker.py
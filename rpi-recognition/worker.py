@@ -0,0 +1,38 @@
+# Long-lived classifier, the model is loaded once and then used for every request.
+#
+# Speaks line-delimited JSON over stdin and stdout, stdout is used for nothing else:
+#   <- {"ready": true}                                        once the model is loaded
+#   -> {"id": 1, "image": "<base64 jpeg>", "top_k": 5}
+#   <- {"id": 1, "results": [{"label": "screw", "confidence": 0.93}, ...]}
+#   <- {"id": 1, "error": "cannot identify image file"}       when the request failed
+import base64
+import io
+import json
+import sys
+
+from PIL import Image
+
+from classify import top_k
+
+def handle(request):
+    image = Image.open(io.BytesIO(base64.b64decode(request["image"])))
+    results = top_k(image, request.get("top_k", 5))
+    return {"results": [{"label": label, "confidence": score} for label, score in results]}
+
+def reply(message):
+    sys.stdout.write(json.dumps(message) + "\n")
+    sys.stdout.flush()
+
+reply({"ready": True})
+for line in sys.stdin:
+    if not line.strip():
+        continue
+    request_id = None
+    try:
+        request = json.loads(line)
+        request_id = request["id"]
+        response = handle(request)
+    except Exception as e:
+        response = {"error": str(e)}
+    response["id"] = request_id
+    reply(response)
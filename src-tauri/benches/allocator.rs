@@ -0,0 +1,272 @@
+//! Packs the same random items with every placement heuristic and with the brute-force scan the
+//! allocator used before them, run with `cargo bench --features bench`
+
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+
+use inert_lib::bench::{AllocatorConfig, ItemAllocator, PlacementHeuristic};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+
+const WIDTH: usize = 400;
+const HEIGHT: usize = 300;
+/// Items offered to the allocator per run, more than fit on the plane
+const ITEMS: usize = 60;
+const RUNS: u64 = 10;
+
+fn main() {
+    for heuristic in [
+        PlacementHeuristic::BestShortSideFit,
+        PlacementHeuristic::BestAreaFit,
+        PlacementHeuristic::BottomLeft,
+    ] {
+        measure(&format!("{heuristic:?}"), |items| {
+            // The old scan knows nothing about gaps and keep-out zones
+            let config = AllocatorConfig {
+                gap_mm: 0,
+                access_point_clearance_mm: 0,
+                keep_out: Vec::new(),
+            };
+            let mut allocator = ItemAllocator::with_items(WIDTH, HEIGHT, &config, Vec::new());
+            allocator.set_heuristic(heuristic);
+
+            items
+                .iter()
+                .filter(|&&(width, height)| allocator.allocate(width, height).is_some())
+                .map(|(width, height)| width * height)
+                .sum()
+        });
+    }
+
+    measure("BruteForceScan", |items| {
+        let mut allocator = BruteForceScan::new(WIDTH, HEIGHT);
+
+        items
+            .iter()
+            .filter(|&&(width, height)| allocator.allocate(width, height).is_some())
+            .map(|(width, height)| width * height)
+            .sum()
+    });
+}
+
+/// Time `place` over `RUNS` item lists, it returns the area of the items it placed
+fn measure(name: &str, place: impl Fn(&[(usize, usize)]) -> usize) {
+    let mut elapsed = Duration::ZERO;
+    let mut packed = 0;
+
+    for seed in 0..RUNS {
+        let items = items(seed);
+
+        let start = Instant::now();
+        packed += place(&items);
+        elapsed += start.elapsed();
+    }
+
+    let plane = (WIDTH * HEIGHT) as f64 * RUNS as f64;
+    println!(
+        "{name:<18} {:>12.2?} per run {:>6.1}% packed",
+        elapsed / RUNS as u32,
+        packed as f64 / plane * 100.0
+    );
+}
+
+/// Mostly the size classes of the add item popup, some custom sizes, in millimetres
+fn items(seed: u64) -> Vec<(usize, usize)> {
+    let mut rng = StdRng::seed_from_u64(seed);
+
+    (0..ITEMS)
+        .map(|_| match rng.random_range(0..4) {
+            0 => (40, 40),
+            1 => (60, 60),
+            2 => (100, 100),
+            _ => (rng.random_range(20..120), rng.random_range(20..120)),
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Rect {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+}
+
+/// The allocator before the maximal rectangles, it tries every free millimetre in order
+struct BruteForceScan {
+    free_list: Vec<Rect>,
+}
+
+impl BruteForceScan {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            free_list: vec![Rect {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            }],
+        }
+    }
+
+    fn allocate(&mut self, width: usize, height: usize) -> Option<Rect> {
+        for free in &self.free_list {
+            for x in free.x..free.x + free.width {
+                for y in free.y..free.y + free.height {
+                    if self.is_valid_in_free_list(x, y, width, height) {
+                        let mut queue = VecDeque::new();
+                        queue.push_back(Rect {
+                            x,
+                            y,
+                            width,
+                            height,
+                        });
+
+                        let mut new_free = Vec::new();
+
+                        while let Some(rect) = queue.pop_front() {
+                            let mut i = 0;
+                            while i < self.free_list.len() {
+                                let space = &self.free_list[i];
+
+                                if let Some(overlap) = get_overlap(&rect, space) {
+                                    let remaining_parts = subtract_rect(&rect, &overlap);
+                                    for r in remaining_parts {
+                                        queue.push_back(r);
+                                    }
+                                    let new_space = subtract_rect(space, &rect);
+
+                                    self.free_list.remove(i);
+                                    new_free.extend(new_space);
+
+                                    break;
+                                } else {
+                                    i += 1;
+                                }
+                            }
+                        }
+
+                        self.free_list.extend_from_slice(&new_free);
+
+                        return Some(Rect {
+                            x,
+                            y,
+                            width,
+                            height,
+                        });
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    fn is_valid_in_free_list(&self, x: usize, y: usize, width: usize, height: usize) -> bool {
+        let mut queue = VecDeque::new();
+        queue.push_back(Rect {
+            x,
+            y,
+            width,
+            height,
+        });
+
+        while let Some(rect) = queue.pop_front() {
+            let mut found_overlap = false;
+
+            for space in &self.free_list {
+                if let Some(overlap) = get_overlap(&rect, space) {
+                    found_overlap = true;
+
+                    // Subtract the overlapping part, queue the remaining regions
+                    let remaining_parts = subtract_rect(&rect, &overlap);
+                    for r in remaining_parts {
+                        queue.push_back(r);
+                    }
+
+                    break;
+                }
+            }
+
+            if !found_overlap {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+/// Subtracts `sub` from `rect`, returns the list of remaining rectangles
+fn subtract_rect(rect: &Rect, sub: &Rect) -> Vec<Rect> {
+    let mut result = Vec::new();
+
+    let rect_right = rect.x + rect.width;
+    let rect_bottom = rect.y + rect.height;
+    let sub_right = sub.x + sub.width;
+    let sub_bottom = sub.y + sub.height;
+
+    // Top
+    if sub.y > rect.y {
+        result.push(Rect {
+            x: rect.x,
+            y: rect.y,
+            width: rect.width,
+            height: sub.y - rect.y,
+        });
+    }
+
+    // Bottom
+    if sub_bottom < rect_bottom {
+        result.push(Rect {
+            x: rect.x,
+            y: sub_bottom,
+            width: rect.width,
+            height: rect_bottom - sub_bottom,
+        });
+    }
+
+    // Left
+    if sub.x > rect.x {
+        let top = sub.y.max(rect.y);
+        let bottom = sub_bottom.min(rect_bottom);
+        result.push(Rect {
+            x: rect.x,
+            y: top,
+            width: sub.x - rect.x,
+            height: bottom - top,
+        });
+    }
+
+    // Right
+    if sub_right < rect_right {
+        let top = sub.y.max(rect.y);
+        let bottom = sub_bottom.min(rect_bottom);
+        result.push(Rect {
+            x: sub_right,
+            y: top,
+            width: rect_right - sub_right,
+            height: bottom - top,
+        });
+    }
+
+    result
+}
+
+fn get_overlap(a: &Rect, b: &Rect) -> Option<Rect> {
+    let x1 = a.x.max(b.x);
+    let y1 = a.y.max(b.y);
+    let x2 = (a.x + a.width).min(b.x + b.width);
+    let y2 = (a.y + a.height).min(b.y + b.height);
+
+    if x1 < x2 && y1 < y2 {
+        Some(Rect {
+            x: x1,
+            y: y1,
+            width: x2 - x1,
+            height: y2 - y1,
+        })
+    } else {
+        None
+    }
+}
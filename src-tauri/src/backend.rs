@@ -1,7 +1,21 @@
-use std::ops::Not;
+use std::{
+    ops::Not,
+    time::{Duration, Instant},
+};
 
 use thiserror::Error;
-use tokio::sync::{Mutex, MutexGuard};
+use tokio::{
+    sync::{Mutex, MutexGuard},
+    task::JoinError,
+};
+
+use crate::backend::{
+    calibration::{AxisCalibration, Calibration, CalibrationError},
+    emergency_stop::EmergencyStop,
+};
+
+pub mod calibration;
+pub mod emergency_stop;
 
 #[cfg(feature = "rpi")]
 pub type Backend = BackendImpl<rpi::RpiBackend>;
@@ -23,45 +37,154 @@ pub struct BackendImpl<B: BackendComponents> {
     motor_x: Mutex<B::Motor>,
     motor_y: Mutex<B::Motor>,
 
+    axis_x: Mutex<AxisState>,
+    axis_y: Mutex<AxisState>,
+
     limit_x_l: Mutex<B::Limit>,
     limit_x_r: Mutex<B::Limit>,
     limit_y_l: Mutex<B::Limit>,
     limit_y_r: Mutex<B::Limit>,
+
+    emergency_stop: EmergencyStop,
 }
 
-impl<B: BackendComponents> BackendImpl<B> {
-    pub fn new() -> Self {
-        Self {
-            actuator: B::actuator().into(),
-            magnet: B::magnet().into(),
-            camera: B::camera().into(),
+/// Calibration of an axis along with the last direction it travelled in, for backlash compensation
+#[derive(Debug, Default)]
+struct AxisState {
+    calibration: AxisCalibration,
+    last_direction: Option<MotorDirection>,
+}
 
-            motor_x: B::motor_x().into(),
-            motor_y: B::motor_y().into(),
+impl<B: BackendComponents> BackendImpl<B> {
+    pub async fn new() -> Result<Self, BackendError> {
+        let calibration = Calibration::load().await.unwrap_or_else(|err| {
+            // Still start up so the machine can be calibrated again
+            println!("Using the default calibration: {err}");
+            Calibration::default()
+        });
+        Self::with_components(B::load(), calibration).map_err(|e| BackendError::Setup(e.into()))
+    }
 
-            limit_x_l: B::limit_x_l().into(),
-            limit_x_r: B::limit_x_r().into(),
-            limit_y_l: B::limit_y_l().into(),
-            limit_y_r: B::limit_y_r().into(),
+    fn with_components(
+        components: Result<B, B::Error>,
+        calibration: Calibration,
+    ) -> Result<Self, B::Error> {
+        let mut components = components?;
+        let emergency_stop = EmergencyStop::default();
+
+        if let Some(mut button) = components.emergency_stop()? {
+            let emergency_stop = emergency_stop.clone();
+            std::thread::spawn(move || {
+                loop {
+                    if button.is_pressed() {
+                        emergency_stop.trigger();
+                    }
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+            });
         }
+
+        Ok(Self {
+            actuator: components.actuator(emergency_stop.clone())?.into(),
+            magnet: components.magnet()?.into(),
+            camera: components.camera()?.into(),
+
+            motor_x: components.motor_x()?.into(),
+            motor_y: components.motor_y()?.into(),
+
+            axis_x: AxisState {
+                calibration: calibration.x,
+                last_direction: None,
+            }
+            .into(),
+            axis_y: AxisState {
+                calibration: calibration.y,
+                last_direction: None,
+            }
+            .into(),
+
+            limit_x_l: components.limit_x_l()?.into(),
+            limit_x_r: components.limit_x_r()?.into(),
+            limit_y_l: components.limit_y_l()?.into(),
+            limit_y_r: components.limit_y_r()?.into(),
+
+            emergency_stop,
+        })
     }
 
     pub async fn motor_y(&self) -> ProtectedMotor<'_, B::Motor, B::Limit> {
         ProtectedMotor {
             motor: self.motor_y.lock().await,
+            axis: self.axis_y.lock().await,
             limit_l: self.limit_y_l.lock().await,
             limit_r: self.limit_y_r.lock().await,
+            emergency_stop: self.emergency_stop.clone(),
         }
     }
 
     pub async fn motor_x(&self) -> ProtectedMotor<'_, B::Motor, B::Limit> {
         ProtectedMotor {
             motor: self.motor_x.lock().await,
+            axis: self.axis_x.lock().await,
             limit_l: self.limit_x_l.lock().await,
             limit_r: self.limit_x_r.lock().await,
+            emergency_stop: self.emergency_stop.clone(),
         }
     }
 
+    pub fn emergency_stop(&self) -> &EmergencyStop {
+        &self.emergency_stop
+    }
+
+    /// Release the magnet after an emergency stop, but only when the actuator is extended so
+    /// whatever it holds is already resting on the plane instead of being dropped
+    pub async fn secure(&self) -> Result<(), BackendError> {
+        if self.actuator().await.is_extended() {
+            self.magnet().await.set(false).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn calibration(&self) -> Calibration {
+        Calibration {
+            x: self.axis_x.lock().await.calibration,
+            y: self.axis_y.lock().await.calibration,
+        }
+    }
+
+    /// Replace the current calibration and save it to disk, nothing changes if it can't be saved
+    pub async fn set_calibration(&self, calibration: Calibration) -> Result<(), BackendError> {
+        calibration.save().await?;
+
+        *self.axis_x.lock().await = AxisState {
+            calibration: calibration.x,
+            last_direction: None,
+        };
+        *self.axis_y.lock().await = AxisState {
+            calibration: calibration.y,
+            last_direction: None,
+        };
+        Ok(())
+    }
+
+    /// Estimate of how long the head takes to move between two points in millimetres, both axes
+    /// move together so the longer one sets the time
+    pub async fn travel_time(
+        &self,
+        (from_x, from_y): (usize, usize),
+        (to_x, to_y): (usize, usize),
+        profile: MotionProfile,
+    ) -> Duration {
+        let calibration = self.calibration().await;
+        let x_steps = calibration.x.steps_for(from_x.abs_diff(to_x) as f32);
+        let y_steps = calibration.y.steps_for(from_y.abs_diff(to_y) as f32);
+
+        let x = profile.duration(x_steps, self.motor_x.lock().await.steps_per_turn());
+        let y = profile.duration(y_steps, self.motor_y.lock().await.steps_per_turn());
+        x.max(y)
+    }
+
     pub async fn motor_x_raw(&self) -> MutexGuard<'_, B::Motor> {
         self.motor_x.lock().await
     }
@@ -85,68 +208,320 @@ impl<B: BackendComponents> BackendImpl<B> {
 
 pub struct ProtectedMotor<'a, M: MotorBackend, S: LimitSwitchBackend> {
     motor: MutexGuard<'a, M>,
+    axis: MutexGuard<'a, AxisState>,
     limit_l: MutexGuard<'a, S>,
     limit_r: MutexGuard<'a, S>,
+    emergency_stop: EmergencyStop,
+}
+
+#[derive(Debug, Error)]
+pub enum BackendError {
+    #[error("failed to set up the machine: {0}")]
+    Setup(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("failed to start the camera: {0}")]
+    CameraStart(#[source] std::io::Error),
+    #[error("failed to stop the camera: {0}")]
+    CameraStop(#[source] std::io::Error),
+    #[error("the camera isn't streaming any frames")]
+    NoFrame,
+    #[error("a motion task has crashed: {0}")]
+    Task(#[from] JoinError),
+    #[error("the emergency stop is engaged")]
+    EmergencyStop,
+    #[error(transparent)]
+    Calibration(#[from] CalibrationError),
 }
 
 #[derive(Debug, Error)]
 pub enum ProtectedMotorError {
-    #[error("motor limit switch has hit program trying to rotate pass that by {left_over} block")]
+    #[error("motor limit switch has hit program trying to rotate pass that by {left_over} mm")]
     LimitHit { left_over: usize },
+    #[error(transparent)]
+    Backend(#[from] BackendError),
 }
 
+const STEP_BACK_AMOUNT: f32 = 0.25;
+
 impl<'a, M: MotorBackend, S: LimitSwitchBackend> ProtectedMotor<'a, M, S> {
-    pub async fn rotate_block(
+    /// Move the axis by `amount` millimetres, stops early if the limit switch in `direction` is hit
+    pub async fn rotate_mm(
         &mut self,
-        motor_direction: MotorDirection,
+        direction: MotorDirection,
         amount: usize,
+        profile: MotionProfile,
     ) -> Result<(), ProtectedMotorError> {
         if amount == 0 {
             return Ok(());
         }
 
-        const BLOCK_TURN: f32 = 0.07;
-
+        let steps_per_turn = self.motor.steps_per_turn() as f32;
+        let backlash = self.backlash_steps(direction) as f32 / steps_per_turn;
         let rotation_need = MotorRotation {
-            turns: BLOCK_TURN * amount as f32,
+            turns: self.axis.calibration.steps_for(amount as f32) as f32 / steps_per_turn,
         };
 
         let moved = self
             .motor
-            .rotate(motor_direction, rotation_need, || match motor_direction {
-                MotorDirection::AntiClockwise => self.limit_r.is_pressed(),
-                MotorDirection::Clockwise => self.limit_l.is_pressed(),
-            })
+            .rotate(
+                self.axis.calibration.motor_direction(direction),
+                MotorRotation {
+                    turns: rotation_need.turns + backlash,
+                },
+                profile,
+                || {
+                    let limit_pressed = match direction {
+                        MotorDirection::AntiClockwise => self.limit_r.is_pressed(),
+                        MotorDirection::Clockwise => self.limit_l.is_pressed(),
+                    };
+
+                    if self.emergency_stop.is_triggered() {
+                        StepControl::Stop
+                    } else if limit_pressed {
+                        StepControl::StepBack
+                    } else {
+                        StepControl::Continue
+                    }
+                },
+            )
             .await;
+        let moved = match moved {
+            Ok(moved) => moved,
+            Err(err) => {
+                // No idea how far the motor got, so the backlash has to be taken up again
+                self.axis.last_direction = None;
+                return Err(err.into());
+            }
+        };
+        self.axis.last_direction = Some(direction);
 
-        let missed_turns = (rotation_need.turns - moved.turns).max(0.0);
+        let missed_turns = (rotation_need.turns + backlash - moved.turns)
+            .max(0.0)
+            .min(rotation_need.turns);
         if missed_turns > self.motor.epsilon() {
             return Err(ProtectedMotorError::LimitHit {
-                left_over: (missed_turns as f32 / BLOCK_TURN).round() as usize,
+                left_over: (missed_turns * steps_per_turn / self.axis.calibration.steps_per_mm)
+                    .round() as usize,
             });
         }
 
         Ok(())
     }
+
+    /// Extra steps needed to take up the backlash before actually moving in `direction`
+    fn backlash_steps(&self, direction: MotorDirection) -> usize {
+        match self.axis.last_direction {
+            Some(last) if last != direction => self
+                .axis
+                .calibration
+                .steps_for(self.axis.calibration.backlash_mm),
+            _ => 0,
+        }
+    }
+
+    fn is_limit_pressed(&mut self, motor_direction: MotorDirection) -> bool {
+        match motor_direction {
+            MotorDirection::AntiClockwise => self.limit_r.is_pressed(),
+            MotorDirection::Clockwise => self.limit_l.is_pressed(),
+        }
+    }
 }
 
-pub trait BackendComponents {
+/// Move two axes by millimetres so both of them start and finish at the same time, the steps of
+/// the shorter move get interleaved Bresenham style into the longer one, which makes the head
+/// travel in a straight line. The ramp follows the motor with the most steps.
+///
+/// An axis that hits its limit switch stops and steps back while the other one keeps going.
+pub async fn rotate_mm_linear<'a, M: MotorBackend, S: LimitSwitchBackend>(
+    (motor_a, direction_a, amount_a): (&mut ProtectedMotor<'a, M, S>, MotorDirection, usize),
+    (motor_b, direction_b, amount_b): (&mut ProtectedMotor<'a, M, S>, MotorDirection, usize),
+    profile: MotionProfile,
+) -> (
+    Result<(), ProtectedMotorError>,
+    Result<(), ProtectedMotorError>,
+) {
+    let travel = [
+        motor_a.axis.calibration.steps_for(amount_a as f32),
+        motor_b.axis.calibration.steps_for(amount_b as f32),
+    ];
+    let backlash = [
+        if amount_a == 0 {
+            0
+        } else {
+            motor_a.backlash_steps(direction_a)
+        },
+        if amount_b == 0 {
+            0
+        } else {
+            motor_b.backlash_steps(direction_b)
+        },
+    ];
+    let steps = [travel[0] + backlash[0], travel[1] + backlash[1]];
+    let major = steps[0].max(steps[1]);
+    let (major_motor, major_steps_per_turn) = if steps[0] >= steps[1] {
+        (0, motor_a.motor.steps_per_turn())
+    } else {
+        (1, motor_b.motor.steps_per_turn())
+    };
+
+    let motor_direction_a = motor_a.axis.calibration.motor_direction(direction_a);
+    let motor_direction_b = motor_b.axis.calibration.motor_direction(direction_b);
+    if let Err(err) = motor_a.motor.set_direction(motor_direction_a).await {
+        return (Err(err.into()), Ok(()));
+    }
+    if let Err(err) = motor_b.motor.set_direction(motor_direction_b).await {
+        return (Ok(()), Err(err.into()));
+    }
+
+    let mut taken = [0usize; 2];
+    let mut halted = [false; 2];
+    let mut error = [major / 2; 2];
+
+    for i in 0..major {
+        if motor_a.emergency_stop.is_triggered() {
+            motor_a.axis.last_direction = None;
+            motor_b.axis.last_direction = None;
+            return (
+                Err(BackendError::EmergencyStop.into()),
+                Err(BackendError::EmergencyStop.into()),
+            );
+        }
+
+        let mut pulsed = [None; 2];
+
+        for (axis, (motor, direction)) in
+            [(&mut *motor_a, direction_a), (&mut *motor_b, direction_b)]
+                .into_iter()
+                .enumerate()
+        {
+            if halted[axis] {
+                continue;
+            }
+
+            error[axis] += steps[axis];
+            if error[axis] < major {
+                continue;
+            }
+            error[axis] -= major;
+
+            pulsed[axis] = Some(motor.motor.pulse());
+            taken[axis] += 1;
+            halted[axis] = motor.is_limit_pressed(direction);
+        }
+
+        let interval = profile.step_interval(i, major, major_steps_per_turn);
+        let since = pulsed[major_motor]
+            .or(pulsed[1 - major_motor])
+            .unwrap_or_else(Instant::now);
+        match major_motor {
+            0 => motor_a.motor.delay(since, interval),
+            _ => motor_b.motor.delay(since, interval),
+        }
+
+        if halted.iter().all(|h| *h) {
+            break;
+        }
+    }
+
+    (
+        finish_linear(
+            motor_a,
+            direction_a,
+            travel[0],
+            backlash[0],
+            taken[0],
+            halted[0],
+            profile,
+        )
+        .await,
+        finish_linear(
+            motor_b,
+            direction_b,
+            travel[1],
+            backlash[1],
+            taken[1],
+            halted[1],
+            profile,
+        )
+        .await,
+    )
+}
+
+async fn finish_linear<M: MotorBackend, S: LimitSwitchBackend>(
+    motor: &mut ProtectedMotor<'_, M, S>,
+    direction: MotorDirection,
+    travel: usize,
+    backlash: usize,
+    mut taken: usize,
+    halted: bool,
+    profile: MotionProfile,
+) -> Result<(), ProtectedMotorError> {
+    let steps_per_turn = motor.motor.steps_per_turn() as f32;
+    if travel + backlash > 0 {
+        motor.axis.last_direction = Some(direction);
+    }
+
+    if halted {
+        let step_back = MotorRotation {
+            turns: (STEP_BACK_AMOUNT * steps_per_turn)
+                .round()
+                .min(taken as f32)
+                / steps_per_turn,
+        };
+        let emergency_stop = &motor.emergency_stop;
+        let stepped_back = motor
+            .motor
+            .rotate(
+                !motor.axis.calibration.motor_direction(direction),
+                step_back,
+                profile,
+                || {
+                    if emergency_stop.is_triggered() {
+                        StepControl::Stop
+                    } else {
+                        StepControl::Continue
+                    }
+                },
+            )
+            .await?;
+        taken -= (stepped_back.turns * steps_per_turn).round() as usize;
+    }
+
+    let missed_steps = (travel + backlash).saturating_sub(taken).min(travel);
+    if missed_steps as f32 / steps_per_turn > motor.motor.epsilon() {
+        return Err(ProtectedMotorError::LimitHit {
+            left_over: (missed_steps as f32 / motor.axis.calibration.steps_per_mm).round() as usize,
+        });
+    }
+
+    Ok(())
+}
+
+pub trait BackendComponents: Sized {
+    type Error: std::error::Error + Send + Sync + 'static;
+
     type Motor: MotorBackend;
     type Camera: CameraBackend;
     type Actuator: ActuatorBackend;
     type Magnet: MagnetBackend;
-    type Limit: LimitSwitchBackend;
+    type Limit: LimitSwitchBackend + Send + 'static;
+
+    /// Read the machine configuration, the components are then created from it
+    fn load() -> Result<Self, Self::Error>;
 
-    fn motor_x() -> Self::Motor;
-    fn motor_y() -> Self::Motor;
-    fn actuator() -> Self::Actuator;
-    fn magnet() -> Self::Magnet;
-    fn camera() -> Self::Camera;
+    fn motor_x(&mut self) -> Result<Self::Motor, Self::Error>;
+    fn motor_y(&mut self) -> Result<Self::Motor, Self::Error>;
+    /// The actuator has to stop travelling as soon as `emergency_stop` is triggered
+    fn actuator(&mut self, emergency_stop: EmergencyStop) -> Result<Self::Actuator, Self::Error>;
+    fn magnet(&mut self) -> Result<Self::Magnet, Self::Error>;
+    fn camera(&mut self) -> Result<Self::Camera, Self::Error>;
 
-    fn limit_x_l() -> Self::Limit;
-    fn limit_x_r() -> Self::Limit;
-    fn limit_y_l() -> Self::Limit;
-    fn limit_y_r() -> Self::Limit;
+    fn limit_x_l(&mut self) -> Result<Self::Limit, Self::Error>;
+    fn limit_x_r(&mut self) -> Result<Self::Limit, Self::Error>;
+    fn limit_y_l(&mut self) -> Result<Self::Limit, Self::Error>;
+    fn limit_y_r(&mut self) -> Result<Self::Limit, Self::Error>;
+
+    /// Physical emergency stop button, if the machine has one
+    fn emergency_stop(&mut self) -> Result<Option<Self::Limit>, Self::Error>;
 }
 
 pub trait LimitSwitchBackend {
@@ -154,12 +529,18 @@ pub trait LimitSwitchBackend {
 }
 
 pub trait ActuatorBackend {
-    async fn contract(&mut self);
-    async fn extend(&mut self);
+    async fn contract(&mut self) -> Result<(), BackendError>;
+    async fn extend(&mut self) -> Result<(), BackendError>;
+
+    /// Whether the last extend finished, false while travelling or after being interrupted
+    fn is_extended(&self) -> bool;
+
+    /// How long a full extend or contract takes
+    fn travel_time(&self) -> Duration;
 }
 
 pub trait MagnetBackend {
-    async fn set(&mut self, on: bool);
+    async fn set(&mut self, on: bool) -> Result<(), BackendError>;
 }
 
 pub trait MotorBackend {
@@ -167,8 +548,24 @@ pub trait MotorBackend {
         &mut self,
         direction: MotorDirection,
         rotation: MotorRotation,
-        should_step_back_and_stop: impl FnMut() -> bool,
-    ) -> MotorRotation;
+        profile: MotionProfile,
+        control: impl FnMut() -> StepControl,
+    ) -> Result<MotorRotation, BackendError>;
+
+    /// Set the direction used by the following [`MotorBackend::pulse`] calls
+    async fn set_direction(&mut self, direction: MotorDirection) -> Result<(), BackendError>;
+
+    /// Step the motor once in the current direction, returns when the step pulse finished
+    fn pulse(&mut self) -> Instant;
+
+    /// Block until `interval` has passed since a step pulse finished at `since`
+    fn delay(&mut self, since: Instant, interval: Duration) {
+        while since.elapsed() < interval {
+            core::hint::spin_loop();
+        }
+    }
+
+    fn steps_per_turn(&self) -> usize;
 
     fn epsilon(&self) -> f32 {
         0.0
@@ -176,20 +573,30 @@ pub trait MotorBackend {
 }
 
 pub trait CameraFrame {
-    async fn take(self) -> Option<Vec<u8>>;
+    async fn take(self) -> Result<Vec<u8>, BackendError>;
 }
 
 pub trait CameraBackend {
     type FrameType: CameraFrame;
 
     /// Start the camera server and return a url to that
-    async fn start(&mut self) -> String;
+    async fn start(&mut self) -> Result<String, BackendError>;
 
     /// Capture a single frame
     fn capture(&mut self) -> Self::FrameType;
 
     /// Stop the camera server
-    async fn stop(&mut self);
+    async fn stop(&mut self) -> Result<(), BackendError>;
+}
+
+/// What [`MotorBackend::rotate`] should do after a step
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepControl {
+    Continue,
+    /// A limit switch was hit, back off a bit and return the steps actually travelled
+    StepBack,
+    /// Stop right away and fail with [`BackendError::EmergencyStop`]
+    Stop,
 }
 
 /// Respresent MotorRotation in turns
@@ -225,7 +632,76 @@ impl From<MotorRotation> for f32 {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Velocity profile used to ramp the step interval up at the start of a move and back down
+/// at the end of it.
+///
+/// Every value is in motor turns: velocities in turns/s, `acceleration` in turns/s² and `jerk`
+/// in turns/s³. Without a `jerk` limit the ramp is a plain trapezoid, with one the start of the
+/// ramp is rounded off into an S-curve so the load isn't kicked from standstill.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MotionProfile {
+    pub start_velocity: f32,
+    pub max_velocity: f32,
+    pub acceleration: f32,
+    pub jerk: Option<f32>,
+}
+
+impl MotionProfile {
+    /// Profile for moving the empty head around
+    pub fn fast() -> Self {
+        Self {
+            start_velocity: 0.3,
+            max_velocity: 1.5625,
+            acceleration: 4.0,
+            jerk: Some(40.0),
+        }
+    }
+
+    /// Profile for when the head is carrying an item
+    pub fn gentle() -> Self {
+        Self {
+            start_velocity: 0.2,
+            max_velocity: 0.8,
+            acceleration: 1.5,
+            jerk: Some(15.0),
+        }
+    }
+
+    /// Velocity in turns/s once the motor is `distance` turns away from standstill
+    pub fn velocity_at(&self, distance: f32) -> f32 {
+        let trapezoid = (self.start_velocity.powi(2) + 2.0 * self.acceleration * distance).sqrt();
+        let s_curve = self.jerk.map_or(f32::INFINITY, |jerk| {
+            self.start_velocity + 0.5 * jerk * (6.0 * distance / jerk).cbrt().powi(2)
+        });
+
+        trapezoid.min(s_curve).min(self.max_velocity)
+    }
+
+    /// How long a move that is `steps` long takes, timed the same way the motors step
+    pub fn duration(&self, steps: usize, steps_per_turn: usize) -> Duration {
+        (0..steps)
+            .map(|step| self.step_interval(step, steps, steps_per_turn))
+            .sum()
+    }
+
+    /// Time to wait after step `step` of a move that is `steps` long
+    pub fn step_interval(&self, step: usize, steps: usize, steps_per_turn: usize) -> Duration {
+        let from_standstill = step.min(steps.saturating_sub(step + 1));
+        let velocity = self
+            .velocity_at(from_standstill as f32 / steps_per_turn as f32)
+            .max(f32::EPSILON);
+
+        Duration::from_secs_f32(1.0 / (velocity * steps_per_turn as f32))
+    }
+}
+
+impl Default for MotionProfile {
+    fn default() -> Self {
+        Self::fast()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MotorDirection {
     Clockwise,
     AntiClockwise,
@@ -0,0 +1,83 @@
+use std::{io, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use ts_rs::TS;
+
+use crate::{
+    backend::MotorDirection,
+    data_file::{self, DataFileError},
+};
+
+/// Per axis calibration, maps millimetres on the plane to motor steps
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct AxisCalibration {
+    pub steps_per_mm: f32,
+    /// Play in the drive train that has to be taken up every time the axis reverses direction
+    pub backlash_mm: f32,
+    /// Set when the motor is wired so it turns the opposite way to the rest of the machine
+    pub inverted: bool,
+}
+
+impl AxisCalibration {
+    /// Direction the motor has to turn for the axis to travel in `direction`
+    pub fn motor_direction(&self, direction: MotorDirection) -> MotorDirection {
+        if self.inverted { !direction } else { direction }
+    }
+
+    pub fn steps_for(&self, mm: f32) -> usize {
+        (mm * self.steps_per_mm).round() as usize
+    }
+}
+
+impl Default for AxisCalibration {
+    fn default() -> Self {
+        // GT2 belt on a 20 tooth pulley (40mm per turn) at 3200 steps per turn
+        Self {
+            steps_per_mm: 80.0,
+            backlash_mm: 0.0,
+            inverted: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct Calibration {
+    pub x: AxisCalibration,
+    pub y: AxisCalibration,
+}
+
+#[derive(Debug, Error)]
+pub enum CalibrationError {
+    #[error("failed to access the calibration file {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("invalid calibration file {}: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+}
+
+impl From<DataFileError> for CalibrationError {
+    fn from(err: DataFileError) -> Self {
+        match err {
+            DataFileError::Io { path, source } => Self::Io { path, source },
+            DataFileError::Parse { path, source } => Self::Parse { path, source },
+        }
+    }
+}
+
+impl Calibration {
+    const FILE_NAME: &str = "calibration.json";
+
+    /// Load the calibration from the data directory, falls back to the default if none was saved
+    pub async fn load() -> Result<Self, CalibrationError> {
+        Ok(data_file::load(Self::FILE_NAME).await?.unwrap_or_default())
+    }
+
+    pub async fn save(&self) -> Result<(), CalibrationError> {
+        Ok(data_file::save(Self::FILE_NAME, self).await?)
+    }
+}
@@ -0,0 +1,47 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// Latched stop signal shared by everything that moves the machine
+///
+/// Once triggered every motion aborts within a step and new ones refuse to start until it's reset.
+#[derive(Debug, Clone)]
+pub struct EmergencyStop(Arc<watch::Sender<bool>>);
+
+impl Default for EmergencyStop {
+    fn default() -> Self {
+        Self(Arc::new(watch::Sender::new(false)))
+    }
+}
+
+impl EmergencyStop {
+    pub fn trigger(&self) {
+        if !self.0.send_replace(true) {
+            println!("Emergency stop triggered");
+        }
+    }
+
+    pub fn reset(&self) {
+        self.0.send_replace(false);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Wait until the stop is triggered, returns right away if it already is
+    pub async fn triggered(&self) {
+        self.wait_for(true).await
+    }
+
+    /// Wait until the stop is reset, returns right away if it isn't triggered
+    pub async fn released(&self) {
+        self.wait_for(false).await
+    }
+
+    async fn wait_for(&self, triggered: bool) {
+        let mut receiver = self.0.subscribe();
+        // The sender lives as long as `self`, so this can't fail
+        let _ = receiver.wait_for(|state| *state == triggered).await;
+    }
+}
@@ -1,34 +1,23 @@
 use std::time::{Duration, Instant};
 
-use rppal::gpio::Gpio;
-
-use crate::backend::{
-    BackendComponents,
-    rpi::{
-        actuator::LinearActuator, cam_server::CameraServer, drv8825::Drv8825Motor,
-        limit::LimitSwitch, magnet::ElectroMagnet,
+use rppal::gpio::{Gpio, Pin};
+use thiserror::Error;
+
+use crate::{
+    backend::{
+        BackendComponents,
+        emergency_stop::EmergencyStop,
+        rpi::{
+            actuator::LinearActuator, cam_server::CameraServer, config::MachineConfig,
+            drv8825::Drv8825Motor, limit::LimitSwitch, magnet::ElectroMagnet,
+        },
     },
+    config::ConfigError,
 };
 
-const MOTOR_X_STEP_PIN: u8 = 23;
-const MOTOR_X_DIR_PIN: u8 = 24;
-
-const MOTOR_Y_STEP_PIN: u8 = 5;
-const MOTOR_Y_DIR_PIN: u8 = 6;
-
-const LINEAR_FORWARD_PIN: u8 = 13;
-const LINEAR_BACKWARD_PIN: u8 = 16;
-
-const MAGNET_PIN: u8 = 12;
-
-const LIMIT_X_L_PIN: u8 = 4;
-const LIMIT_X_R_PIN: u8 = 17;
-
-const LIMIT_Y_L_PIN: u8 = 27;
-const LIMIT_Y_R_PIN: u8 = 22;
-
 pub mod actuator;
 pub mod cam_server;
+pub mod config;
 pub mod drv8825;
 pub mod limit;
 pub mod magnet;
@@ -39,72 +28,140 @@ fn busy_wait_us(microseconds: u64) {
     while now.elapsed() < wait {}
 }
 
-pub struct RpiBackend;
+#[derive(Debug, Error)]
+pub enum RpiError {
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error("pin {pin} used by `{second}` is already used by `{first}`")]
+    PinConflict {
+        pin: u8,
+        first: &'static str,
+        second: &'static str,
+    },
+    #[error("pin {pin} used by `{name}` is not a valid BCM GPIO number")]
+    InvalidPin { name: &'static str, pin: u8 },
+    #[error("`{name}` has an invalid value")]
+    InvalidValue { name: &'static str },
+    #[error("failed to access the GPIO peripheral: {0}")]
+    Gpio(#[from] rppal::gpio::Error),
+    #[error("failed to acquire pin {pin} for `{name}`: {source}")]
+    Pin {
+        name: &'static str,
+        pin: u8,
+        source: rppal::gpio::Error,
+    },
+}
+
+pub struct RpiBackend {
+    config: MachineConfig,
+    gpio: Gpio,
+}
+
+impl RpiBackend {
+    fn pin(&self, name: &'static str, pin: u8) -> Result<Pin, RpiError> {
+        self.gpio
+            .get(pin)
+            .map_err(|source| RpiError::Pin { name, pin, source })
+    }
+}
 
 impl BackendComponents for RpiBackend {
+    type Error = RpiError;
+
     type Motor = Drv8825Motor;
     type Camera = CameraServer;
     type Actuator = LinearActuator;
     type Magnet = ElectroMagnet;
     type Limit = LimitSwitch;
 
-    fn motor_x() -> Drv8825Motor {
-        let gpio = Gpio::new().expect("Failed to get gpio");
-        Drv8825Motor::new(
-            gpio.get(MOTOR_X_STEP_PIN).unwrap().into_output_low(),
-            gpio.get(MOTOR_X_DIR_PIN).unwrap().into_output_low(),
-        )
-    }
-
-    fn motor_y() -> Drv8825Motor {
-        let gpio = Gpio::new().expect("Failed to get gpio");
+    fn load() -> Result<Self, RpiError> {
+        let config: MachineConfig = crate::config::load("machine.toml", "INERT_MACHINE_CONFIG")?;
+        config.validate()?;
 
-        Drv8825Motor::new(
-            gpio.get(MOTOR_Y_STEP_PIN).unwrap().into_output_low(),
-            gpio.get(MOTOR_Y_DIR_PIN).unwrap().into_output_low(),
-        )
+        Ok(Self {
+            config,
+            gpio: Gpio::new()?,
+        })
     }
 
-    fn actuator() -> LinearActuator {
-        let gpio = Gpio::new().expect("Failed to get gpio");
-
-        LinearActuator::new(
-            gpio.get(LINEAR_FORWARD_PIN).unwrap().into_output_low(),
-            gpio.get(LINEAR_BACKWARD_PIN).unwrap().into_output_low(),
-        )
+    fn motor_x(&mut self) -> Result<Drv8825Motor, RpiError> {
+        let pins = &self.config.pins;
+        Ok(Drv8825Motor::new(
+            self.pin("motor_x_step", pins.motor_x_step)?
+                .into_output_low(),
+            self.pin("motor_x_dir", pins.motor_x_dir)?.into_output_low(),
+            self.config.motor.steps_per_turn,
+        ))
     }
 
-    fn magnet() -> ElectroMagnet {
-        let gpio = Gpio::new().expect("Failed to get gpio");
-
-        ElectroMagnet::new(gpio.get(MAGNET_PIN).unwrap().into_output_low())
+    fn motor_y(&mut self) -> Result<Drv8825Motor, RpiError> {
+        let pins = &self.config.pins;
+        Ok(Drv8825Motor::new(
+            self.pin("motor_y_step", pins.motor_y_step)?
+                .into_output_low(),
+            self.pin("motor_y_dir", pins.motor_y_dir)?.into_output_low(),
+            self.config.motor.steps_per_turn,
+        ))
     }
 
-    fn limit_x_l() -> Self::Limit {
-        let gpio = Gpio::new().expect("Failed to get gpio");
-
-        LimitSwitch::new(gpio.get(LIMIT_X_L_PIN).unwrap().into_input_pullup())
+    fn actuator(&mut self, emergency_stop: EmergencyStop) -> Result<LinearActuator, RpiError> {
+        let pins = &self.config.pins;
+        Ok(LinearActuator::new(
+            self.pin("linear_forward", pins.linear_forward)?
+                .into_output_low(),
+            self.pin("linear_backward", pins.linear_backward)?
+                .into_output_low(),
+            Duration::from_millis(self.config.actuator.travel_time_ms),
+            emergency_stop,
+        ))
     }
 
-    fn limit_x_r() -> Self::Limit {
-        let gpio = Gpio::new().expect("Failed to get gpio");
+    fn magnet(&mut self) -> Result<ElectroMagnet, RpiError> {
+        Ok(ElectroMagnet::new(
+            self.pin("magnet", self.config.pins.magnet)?
+                .into_output_low(),
+        ))
+    }
 
-        LimitSwitch::new(gpio.get(LIMIT_X_R_PIN).unwrap().into_input_pullup())
+    fn limit_x_l(&mut self) -> Result<LimitSwitch, RpiError> {
+        Ok(LimitSwitch::new(
+            self.pin("limit_x_l", self.config.pins.limit_x_l)?
+                .into_input_pullup(),
+        ))
     }
 
-    fn limit_y_l() -> Self::Limit {
-        let gpio = Gpio::new().expect("Failed to get gpio");
+    fn limit_x_r(&mut self) -> Result<LimitSwitch, RpiError> {
+        Ok(LimitSwitch::new(
+            self.pin("limit_x_r", self.config.pins.limit_x_r)?
+                .into_input_pullup(),
+        ))
+    }
 
-        LimitSwitch::new(gpio.get(LIMIT_Y_L_PIN).unwrap().into_input_pullup())
+    fn limit_y_l(&mut self) -> Result<LimitSwitch, RpiError> {
+        Ok(LimitSwitch::new(
+            self.pin("limit_y_l", self.config.pins.limit_y_l)?
+                .into_input_pullup(),
+        ))
     }
 
-    fn limit_y_r() -> Self::Limit {
-        let gpio = Gpio::new().expect("Failed to get gpio");
+    fn limit_y_r(&mut self) -> Result<LimitSwitch, RpiError> {
+        Ok(LimitSwitch::new(
+            self.pin("limit_y_r", self.config.pins.limit_y_r)?
+                .into_input_pullup(),
+        ))
+    }
 
-        LimitSwitch::new(gpio.get(LIMIT_Y_R_PIN).unwrap().into_input_pullup())
+    fn camera(&mut self) -> Result<CameraServer, RpiError> {
+        Ok(CameraServer::new())
     }
 
-    fn camera() -> CameraServer {
-        CameraServer::new()
+    fn emergency_stop(&mut self) -> Result<Option<LimitSwitch>, RpiError> {
+        let Some(pin) = self.config.pins.emergency_stop else {
+            return Ok(None);
+        };
+
+        Ok(Some(LimitSwitch::new(
+            self.pin("emergency_stop", pin)?.into_input_pullup(),
+        )))
     }
 }
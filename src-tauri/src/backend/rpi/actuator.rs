@@ -1,38 +1,85 @@
 use std::time::Duration;
 
 use rppal::gpio::OutputPin;
-use tokio::time::sleep;
+use tokio::{select, time::sleep};
 
-use crate::backend::ActuatorBackend;
+use crate::backend::{ActuatorBackend, BackendError, emergency_stop::EmergencyStop};
 
 pub struct LinearActuator {
     linear_forward: OutputPin,
     linear_backward: OutputPin,
+    travel_time: Duration,
+
+    emergency_stop: EmergencyStop,
+    extended: bool,
 }
 
 impl LinearActuator {
-    pub fn new(linear_forward: OutputPin, linear_backward: OutputPin) -> Self {
+    pub fn new(
+        linear_forward: OutputPin,
+        linear_backward: OutputPin,
+        travel_time: Duration,
+        emergency_stop: EmergencyStop,
+    ) -> Self {
         Self {
             linear_backward,
             linear_forward,
+            travel_time,
+
+            emergency_stop,
+            extended: false,
         }
     }
+
+    /// Drive `pin` for the full travel time, or until the emergency stop is triggered
+    async fn travel(
+        pin: &mut OutputPin,
+        travel_time: Duration,
+        emergency_stop: &EmergencyStop,
+    ) -> Result<(), BackendError> {
+        pin.set_high();
+        let result = select! {
+            _ = sleep(travel_time) => Ok(()),
+            _ = emergency_stop.triggered() => Err(BackendError::EmergencyStop),
+        };
+        pin.set_low();
+
+        result
+    }
 }
 
 impl ActuatorBackend for LinearActuator {
-    async fn contract(&mut self) {
+    async fn contract(&mut self) -> Result<(), BackendError> {
         self.linear_backward.set_low();
+        self.extended = false;
 
-        self.linear_forward.set_high();
-        sleep(Duration::from_secs(5)).await;
-        self.linear_forward.set_low();
+        Self::travel(
+            &mut self.linear_forward,
+            self.travel_time,
+            &self.emergency_stop,
+        )
+        .await
     }
 
-    async fn extend(&mut self) {
+    async fn extend(&mut self) -> Result<(), BackendError> {
         self.linear_forward.set_low();
+        self.extended = false;
 
-        self.linear_backward.set_high();
-        sleep(Duration::from_secs(5)).await;
-        self.linear_backward.set_low();
+        Self::travel(
+            &mut self.linear_backward,
+            self.travel_time,
+            &self.emergency_stop,
+        )
+        .await?;
+        self.extended = true;
+        Ok(())
+    }
+
+    fn is_extended(&self) -> bool {
+        self.extended
+    }
+
+    fn travel_time(&self) -> Duration {
+        self.travel_time
     }
 }
@@ -10,7 +10,7 @@ use tokio::{
 use tokio_util::io::ReaderStream;
 use warp::Filter;
 
-use crate::backend::{CameraBackend, CameraFrame};
+use crate::backend::{BackendError, CameraBackend, CameraFrame};
 
 pub struct CameraServer {
     rpi_cam_process: Option<Child>,
@@ -48,43 +48,48 @@ impl CameraServer {
 pub struct RpiCameraFrame(broadcast::Receiver<Vec<u8>>);
 
 impl CameraFrame for RpiCameraFrame {
-    async fn take(mut self) -> Option<Vec<u8>> {
-        self.0.recv().await.ok()
+    async fn take(mut self) -> Result<Vec<u8>, BackendError> {
+        loop {
+            match self.0.recv().await {
+                Ok(frame) => return Ok(frame),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return Err(BackendError::NoFrame),
+            }
+        }
     }
 }
 
 impl CameraBackend for CameraServer {
     type FrameType = RpiCameraFrame;
 
-    async fn start(&mut self) -> String {
+    async fn start(&mut self) -> Result<String, BackendError> {
         let mut child = Command::new("rpicam-vid")
             .args(["-t", "0", "-n", "--inline", "--codec", "mjpeg", "-o", "-"])
             .stdout(Stdio::piped())
             .stderr(Stdio::null())
             .spawn()
-            .unwrap();
+            .map_err(BackendError::CameraStart)?;
 
-        let stdout = child.stdout.take().expect("no stdout");
+        let stdout = child.stdout.take().expect("stdout is piped");
         self.rpi_cam_server = Some(tokio::spawn(read_mjpeg_stream(stdout, self.tx.clone())));
         self.rpi_cam_process = Some(child);
-        "http://127.0.0.1:3030/video".to_string()
+        Ok("http://127.0.0.1:3030/video".to_string())
     }
 
     fn capture(&mut self) -> Self::FrameType {
         RpiCameraFrame(self.tx.subscribe())
     }
 
-    async fn stop(&mut self) {
-        if let Some(rpi_cam) = self.rpi_cam_process.as_mut() {
-            rpi_cam
-                .kill()
-                .await
-                .expect("Failed to kill rpi-cam process");
+    async fn stop(&mut self) -> Result<(), BackendError> {
+        if let Some(mut rpi_cam) = self.rpi_cam_process.take() {
+            rpi_cam.kill().await.map_err(BackendError::CameraStop)?;
         }
 
-        if let Some(rpi_cam) = self.rpi_cam_server.as_mut() {
-            rpi_cam.await.expect("Failed to kill camera server");
+        if let Some(rpi_cam) = self.rpi_cam_server.take() {
+            rpi_cam.await?;
         }
+
+        Ok(())
     }
 }
 
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::backend::rpi::RpiError;
+
+/// Wiring and timing of a machine, read from `machine.toml`
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MachineConfig {
+    pub pins: PinConfig,
+    pub motor: MotorConfig,
+    pub actuator: ActuatorConfig,
+}
+
+/// BCM numbers of every GPIO pin the machine uses
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PinConfig {
+    pub motor_x_step: u8,
+    pub motor_x_dir: u8,
+    pub motor_y_step: u8,
+    pub motor_y_dir: u8,
+
+    pub linear_forward: u8,
+    pub linear_backward: u8,
+
+    pub magnet: u8,
+
+    pub limit_x_l: u8,
+    pub limit_x_r: u8,
+    pub limit_y_l: u8,
+    pub limit_y_r: u8,
+
+    /// Emergency stop button, wired like the limit switches
+    pub emergency_stop: Option<u8>,
+}
+
+impl Default for PinConfig {
+    fn default() -> Self {
+        Self {
+            motor_x_step: 23,
+            motor_x_dir: 24,
+            motor_y_step: 5,
+            motor_y_dir: 6,
+
+            linear_forward: 13,
+            linear_backward: 16,
+
+            magnet: 12,
+
+            limit_x_l: 4,
+            limit_x_r: 17,
+            limit_y_l: 27,
+            limit_y_r: 22,
+
+            emergency_stop: None,
+        }
+    }
+}
+
+impl PinConfig {
+    /// Every pin in use along with the name of its config key
+    pub fn named(&self) -> Vec<(&'static str, u8)> {
+        let mut pins = vec![
+            ("motor_x_step", self.motor_x_step),
+            ("motor_x_dir", self.motor_x_dir),
+            ("motor_y_step", self.motor_y_step),
+            ("motor_y_dir", self.motor_y_dir),
+            ("linear_forward", self.linear_forward),
+            ("linear_backward", self.linear_backward),
+            ("magnet", self.magnet),
+            ("limit_x_l", self.limit_x_l),
+            ("limit_x_r", self.limit_x_r),
+            ("limit_y_l", self.limit_y_l),
+            ("limit_y_r", self.limit_y_r),
+        ];
+
+        if let Some(pin) = self.emergency_stop {
+            pins.push(("emergency_stop", pin));
+        }
+
+        pins
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MotorConfig {
+    /// Microsteps per turn, 3200 with M2 high and the other M pins low on the DRV8825
+    pub steps_per_turn: usize,
+}
+
+impl Default for MotorConfig {
+    fn default() -> Self {
+        Self {
+            steps_per_turn: 3200,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ActuatorConfig {
+    /// How long the actuator is driven to fully extend or contract
+    pub travel_time_ms: u64,
+}
+
+impl Default for ActuatorConfig {
+    fn default() -> Self {
+        Self {
+            travel_time_ms: 5000,
+        }
+    }
+}
+
+impl MachineConfig {
+    pub fn validate(&self) -> Result<(), RpiError> {
+        let mut used = HashMap::new();
+
+        for (name, pin) in self.pins.named() {
+            if pin > 27 {
+                return Err(RpiError::InvalidPin { name, pin });
+            }
+
+            if let Some(other) = used.insert(pin, name) {
+                return Err(RpiError::PinConflict {
+                    pin,
+                    first: other,
+                    second: name,
+                });
+            }
+        }
+
+        if self.motor.steps_per_turn == 0 {
+            return Err(RpiError::InvalidValue {
+                name: "motor.steps_per_turn",
+            });
+        }
+
+        Ok(())
+    }
+}
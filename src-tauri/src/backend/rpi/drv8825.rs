@@ -3,11 +3,13 @@ use std::time::{Duration, Instant};
 use rppal::gpio::{Level, OutputPin};
 use tokio::time::{sleep, sleep_until};
 
-use crate::backend::{MotorBackend, MotorDirection, MotorRotation, rpi::busy_wait_us};
+use crate::backend::{
+    BackendError, MotionProfile, MotorBackend, MotorDirection, MotorRotation, StepControl,
+    rpi::busy_wait_us,
+};
 
 const STEP_BACK_AMOUNT: f32 = 0.25;
 const MOTOR_STEP_TIME: u64 = 5;
-const MOTOR_WAIT_TIME: u64 = 200;
 
 pub struct Drv8825Motor {
     step_pin: OutputPin,
@@ -16,13 +18,12 @@ pub struct Drv8825Motor {
 }
 
 impl Drv8825Motor {
-    pub fn new(step_pin: OutputPin, dir_pin: OutputPin) -> Self {
+    pub fn new(step_pin: OutputPin, dir_pin: OutputPin, steps_per_turn: usize) -> Self {
         Self {
             step_pin,
             dir_pin,
-            steps_per_turn: 3200,
-        } // Steps per turn is 3200 with M2 high and other 
-        // M pin low
+            steps_per_turn,
+        }
     }
 
     #[inline(always)]
@@ -37,38 +38,39 @@ impl Drv8825Motor {
         &mut self,
         dir: MotorDirection,
         steps: usize,
-        mut should_step_back_and_stop: impl FnMut() -> bool,
-    ) -> usize {
-        self.dir_pin.write(dir.into());
-        sleep(Duration::from_millis(50)).await;
+        profile: MotionProfile,
+        mut control: impl FnMut() -> StepControl,
+    ) -> Result<usize, BackendError> {
+        self.set_direction(dir).await?;
 
         for i in 0..steps {
             let wait = self.step_one();
 
-            if should_step_back_and_stop() {
-                let step_back_steps =
-                    ((STEP_BACK_AMOUNT * self.steps_per_turn as f32).round() as usize).min(i);
+            match control() {
+                StepControl::Continue => {}
+                StepControl::Stop => return Err(BackendError::EmergencyStop),
+                StepControl::StepBack => {
+                    let step_back_steps =
+                        ((STEP_BACK_AMOUNT * self.steps_per_turn as f32).round() as usize).min(i);
 
-                self.dir_pin.write((!dir).into());
-                sleep(Duration::from_millis(50)).await;
+                    self.set_direction(!dir).await?;
 
-                for _ in 0..step_back_steps {
-                    let wait = self.step_one();
-
-                    while wait.elapsed() < Duration::from_micros(MOTOR_WAIT_TIME) {
-                        core::hint::spin_loop();
+                    for j in 0..step_back_steps {
+                        let wait = self.step_one();
+                        let interval =
+                            profile.step_interval(j, step_back_steps, self.steps_per_turn);
+                        self.delay(wait, interval);
                     }
-                }
 
-                return i - step_back_steps;
+                    return Ok(i - step_back_steps);
+                }
             }
 
-            while wait.elapsed() < Duration::from_micros(MOTOR_WAIT_TIME) {
-                core::hint::spin_loop();
-            }
+            let interval = profile.step_interval(i, steps, self.steps_per_turn);
+            self.delay(wait, interval);
         }
 
-        steps
+        Ok(steps)
     }
 }
 
@@ -80,8 +82,8 @@ impl From<MotorDirection> for Level {
         }
     }
 }
-async fn motor_delay_async(wait: Instant) {
-    let target = wait + Duration::from_micros(MOTOR_WAIT_TIME);
+async fn motor_delay_async(wait: Instant, interval: Duration) {
+    let target = wait + interval;
     let now = Instant::now();
 
     if target > now {
@@ -94,26 +96,39 @@ impl MotorBackend for Drv8825Motor {
     async fn rotate(
         &mut self,
         direction: MotorDirection,
-        rotation: crate::backend::MotorRotation,
-        should_step_back_and_stop: impl FnMut() -> bool,
-    ) -> crate::backend::MotorRotation {
+        rotation: MotorRotation,
+        profile: MotionProfile,
+        control: impl FnMut() -> StepControl,
+    ) -> Result<MotorRotation, BackendError> {
         #[cfg(feature = "logging")]
         {
             println!(
-                "RpiMotor Dir: {}, Step: {};  turned, Direction: {direction:?}, Rotation: {rotation:?}",
+                "RpiMotor Dir: {}, Step: {};  turned, Direction: {direction:?}, Rotation: {rotation:?}, Profile: {profile:?}",
                 self.dir_pin.pin(),
                 self.step_pin.pin()
             );
         }
 
         let steps_need = (rotation.turns * self.steps_per_turn as f32).round() as usize;
-        let steps_taken = self
-            .step(direction, steps_need, should_step_back_and_stop)
-            .await;
+        let steps_taken = self.step(direction, steps_need, profile, control).await?;
 
-        MotorRotation {
+        Ok(MotorRotation {
             turns: steps_taken as f32 / self.steps_per_turn as f32,
-        }
+        })
+    }
+
+    async fn set_direction(&mut self, direction: MotorDirection) -> Result<(), BackendError> {
+        self.dir_pin.write(direction.into());
+        sleep(Duration::from_millis(50)).await;
+        Ok(())
+    }
+
+    fn pulse(&mut self) -> Instant {
+        self.step_one()
+    }
+
+    fn steps_per_turn(&self) -> usize {
+        self.steps_per_turn
     }
 
     fn epsilon(&self) -> f32 {
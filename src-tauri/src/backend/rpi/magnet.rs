@@ -1,6 +1,6 @@
 use rppal::gpio::{Level, OutputPin};
 
-use crate::backend::MagnetBackend;
+use crate::backend::{BackendError, MagnetBackend};
 
 pub struct ElectroMagnet {
     magnet_pin: OutputPin,
@@ -13,8 +13,9 @@ impl ElectroMagnet {
 }
 
 impl MagnetBackend for ElectroMagnet {
-    async fn set(&mut self, on: bool) {
+    async fn set(&mut self, on: bool) -> Result<(), BackendError> {
         self.magnet_pin
             .write(if on { Level::High } else { Level::Low });
+        Ok(())
     }
 }
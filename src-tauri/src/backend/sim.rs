@@ -1,95 +1,359 @@
-use crate::backend::{
-    ActuatorBackend, BackendComponents, CameraBackend, CameraFrame, LimitSwitchBackend,
-    MagnetBackend, MotorBackend, MotorDirection, MotorRotation,
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 
-pub struct FakeBackend;
+use serde::Deserialize;
+
+use crate::{
+    backend::{
+        ActuatorBackend, BackendComponents, BackendError, CameraBackend, CameraFrame,
+        LimitSwitchBackend, MagnetBackend, MotionProfile, MotorBackend, MotorDirection,
+        MotorRotation, StepControl, emergency_stop::EmergencyStop,
+    },
+    config::ConfigError,
+};
+
+const STEP_BACK_AMOUNT: f32 = 0.25;
+
+/// Shape of the simulated machine, read from `sim.toml`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SimConfig {
+    /// Distance between the x limit switches
+    pub width_mm: f32,
+    /// Distance between the y limit switches
+    pub height_mm: f32,
+    /// Where the head starts, relative to the home position
+    pub start_x_mm: f32,
+    pub start_y_mm: f32,
+
+    pub steps_per_turn: usize,
+    pub steps_per_mm: f32,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            width_mm: 600.0,
+            height_mm: 400.0,
+            start_x_mm: 300.0,
+            start_y_mm: 200.0,
+
+            steps_per_turn: 3200,
+            steps_per_mm: 80.0,
+        }
+    }
+}
+
+/// Physical state of the simulated machine, shared by every fake component
+#[derive(Debug)]
+pub struct Gantry {
+    x: SimAxis,
+    y: SimAxis,
+
+    actuator_extended: bool,
+    magnet_on: bool,
+    holding_item: bool,
+}
+
+/// Position of a motor in steps, clockwise steps count up
+#[derive(Debug)]
+struct SimAxis {
+    position: i64,
+    direction: MotorDirection,
+
+    /// Position where the limit switch guarding clockwise travel trips
+    clockwise_end: i64,
+    /// Position where the limit switch guarding anticlockwise travel trips
+    anticlockwise_end: i64,
+}
+
+impl SimAxis {
+    fn step(&mut self) {
+        match self.direction {
+            MotorDirection::Clockwise => self.position += 1,
+            MotorDirection::AntiClockwise => self.position -= 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Axis {
+    X,
+    Y,
+}
+
+impl Gantry {
+    fn new(config: &SimConfig) -> Self {
+        let steps = |mm: f32| (mm * config.steps_per_mm).round() as i64;
+
+        // The x axis homes anticlockwise and the y axis homes clockwise, see `PlaneImpl::homeing`
+        Self {
+            x: SimAxis {
+                position: steps(config.start_x_mm),
+                direction: MotorDirection::Clockwise,
+                clockwise_end: steps(config.width_mm),
+                anticlockwise_end: 0,
+            },
+            y: SimAxis {
+                position: -steps(config.start_y_mm),
+                direction: MotorDirection::Clockwise,
+                clockwise_end: 0,
+                anticlockwise_end: -steps(config.height_mm),
+            },
+
+            actuator_extended: false,
+            magnet_on: false,
+            holding_item: false,
+        }
+    }
+
+    fn axis(&mut self, axis: Axis) -> &mut SimAxis {
+        match axis {
+            Axis::X => &mut self.x,
+            Axis::Y => &mut self.y,
+        }
+    }
+}
+
+pub struct FakeBackend {
+    config: SimConfig,
+    gantry: Arc<Mutex<Gantry>>,
+}
 
 impl BackendComponents for FakeBackend {
+    type Error = ConfigError;
+
     type Motor = FakeMotor;
     type Camera = FakeCamera;
     type Actuator = FakeActuator;
     type Magnet = FakeMagnet;
     type Limit = FakeLimit;
 
-    fn limit_y_r() -> Self::Limit {
-        FakeLimit
+    fn load() -> Result<Self, ConfigError> {
+        let config: SimConfig = crate::config::load("sim.toml", "INERT_SIM_CONFIG")?;
+        let gantry = Arc::new(Mutex::new(Gantry::new(&config)));
+
+        Ok(Self { config, gantry })
+    }
+
+    fn limit_y_r(&mut self) -> Result<FakeLimit, ConfigError> {
+        Ok(self.limit(Axis::Y, MotorDirection::AntiClockwise))
     }
 
-    fn limit_y_l() -> Self::Limit {
-        FakeLimit
+    fn limit_y_l(&mut self) -> Result<FakeLimit, ConfigError> {
+        Ok(self.limit(Axis::Y, MotorDirection::Clockwise))
     }
 
-    fn limit_x_r() -> Self::Limit {
-        FakeLimit
+    fn limit_x_r(&mut self) -> Result<FakeLimit, ConfigError> {
+        Ok(self.limit(Axis::X, MotorDirection::AntiClockwise))
     }
 
-    fn limit_x_l() -> Self::Limit {
-        FakeLimit
+    fn limit_x_l(&mut self) -> Result<FakeLimit, ConfigError> {
+        Ok(self.limit(Axis::X, MotorDirection::Clockwise))
     }
 
-    fn motor_x() -> FakeMotor {
-        FakeMotor(1)
+    fn motor_x(&mut self) -> Result<FakeMotor, ConfigError> {
+        Ok(self.motor(Axis::X))
     }
 
-    fn motor_y() -> FakeMotor {
-        FakeMotor(2)
+    fn motor_y(&mut self) -> Result<FakeMotor, ConfigError> {
+        Ok(self.motor(Axis::Y))
     }
 
-    fn actuator() -> FakeActuator {
-        FakeActuator
+    fn actuator(&mut self, emergency_stop: EmergencyStop) -> Result<FakeActuator, ConfigError> {
+        Ok(FakeActuator {
+            gantry: self.gantry.clone(),
+            emergency_stop,
+        })
     }
 
-    fn magnet() -> FakeMagnet {
-        FakeMagnet
+    fn magnet(&mut self) -> Result<FakeMagnet, ConfigError> {
+        Ok(FakeMagnet(self.gantry.clone()))
     }
 
-    fn camera() -> FakeCamera {
-        FakeCamera
+    fn camera(&mut self) -> Result<FakeCamera, ConfigError> {
+        Ok(FakeCamera)
+    }
+
+    fn emergency_stop(&mut self) -> Result<Option<FakeLimit>, ConfigError> {
+        Ok(None)
     }
 }
 
-pub struct FakeLimit;
+impl FakeBackend {
+    fn limit(&self, axis: Axis, guards: MotorDirection) -> FakeLimit {
+        FakeLimit {
+            gantry: self.gantry.clone(),
+            axis,
+            guards,
+        }
+    }
+
+    fn motor(&self, axis: Axis) -> FakeMotor {
+        FakeMotor {
+            gantry: self.gantry.clone(),
+            axis,
+            steps_per_turn: self.config.steps_per_turn,
+        }
+    }
+}
+
+pub struct FakeLimit {
+    gantry: Arc<Mutex<Gantry>>,
+    axis: Axis,
+    /// Direction of travel this switch stops
+    guards: MotorDirection,
+}
 
 impl LimitSwitchBackend for FakeLimit {
     fn is_pressed(&mut self) -> bool {
-        false
+        let mut gantry = self.gantry.lock().unwrap();
+        let axis = gantry.axis(self.axis);
+
+        match self.guards {
+            MotorDirection::Clockwise => axis.position >= axis.clockwise_end,
+            MotorDirection::AntiClockwise => axis.position <= axis.anticlockwise_end,
+        }
     }
 }
 
-pub struct FakeActuator;
+pub struct FakeActuator {
+    gantry: Arc<Mutex<Gantry>>,
+    emergency_stop: EmergencyStop,
+}
 
 impl ActuatorBackend for FakeActuator {
-    async fn contract(&mut self) {
-        println!("FakeActuator contract");
+    async fn contract(&mut self) -> Result<(), BackendError> {
+        if self.emergency_stop.is_triggered() {
+            return Err(BackendError::EmergencyStop);
+        }
+
+        let mut gantry = self.gantry.lock().unwrap();
+        gantry.actuator_extended = false;
+        println!(
+            "FakeActuator contract, holding item: {}",
+            gantry.holding_item
+        );
+        Ok(())
+    }
+    async fn extend(&mut self) -> Result<(), BackendError> {
+        if self.emergency_stop.is_triggered() {
+            return Err(BackendError::EmergencyStop);
+        }
+
+        let mut gantry = self.gantry.lock().unwrap();
+        gantry.actuator_extended = true;
+        if gantry.magnet_on {
+            gantry.holding_item = true;
+        }
+        println!("FakeActuator extend, holding item: {}", gantry.holding_item);
+        Ok(())
+    }
+
+    fn is_extended(&self) -> bool {
+        self.gantry.lock().unwrap().actuator_extended
     }
-    async fn extend(&mut self) {
-        println!("FakeActuator extend");
+
+    fn travel_time(&self) -> Duration {
+        Duration::ZERO
     }
 }
 
-pub struct FakeMagnet;
+pub struct FakeMagnet(Arc<Mutex<Gantry>>);
 
 impl MagnetBackend for FakeMagnet {
-    async fn set(&mut self, on: bool) {
-        println!("FakeMagnet on?: {on}");
+    async fn set(&mut self, on: bool) -> Result<(), BackendError> {
+        let mut gantry = self.0.lock().unwrap();
+        gantry.magnet_on = on;
+
+        match (on, gantry.actuator_extended, gantry.holding_item) {
+            (true, true, false) => {
+                gantry.holding_item = true;
+                println!("FakeMagnet on, picked up an item");
+            }
+            (false, true, true) => {
+                gantry.holding_item = false;
+                println!("FakeMagnet off, placed the item");
+            }
+            (false, false, true) => {
+                gantry.holding_item = false;
+                println!("FakeMagnet off while contracted, dropped the item");
+            }
+            _ => println!("FakeMagnet on?: {on}"),
+        }
+        Ok(())
     }
 }
 
-pub struct FakeMotor(usize);
+pub struct FakeMotor {
+    gantry: Arc<Mutex<Gantry>>,
+    axis: Axis,
+    steps_per_turn: usize,
+}
 
 impl MotorBackend for FakeMotor {
     async fn rotate(
         &mut self,
         direction: MotorDirection,
         rotation: MotorRotation,
-        should_step_back_and_stop: impl FnMut() -> bool,
-    ) -> MotorRotation {
+        profile: MotionProfile,
+        mut control: impl FnMut() -> StepControl,
+    ) -> Result<MotorRotation, BackendError> {
         println!(
-            "FakeMotor {} turned, Direction: {direction:?}, Rotation: {rotation:?}",
-            self.0
+            "FakeMotor {:?} turned, Direction: {direction:?}, Rotation: {rotation:?}, Profile: {profile:?}",
+            self.axis
         );
-        rotation
+
+        let steps = (rotation.turns * self.steps_per_turn as f32).round() as usize;
+        self.set_direction(direction).await?;
+
+        let mut taken = steps;
+        for i in 0..steps {
+            self.pulse();
+
+            match control() {
+                StepControl::Continue => {}
+                StepControl::Stop => return Err(BackendError::EmergencyStop),
+                StepControl::StepBack => {
+                    let step_back_steps =
+                        ((STEP_BACK_AMOUNT * self.steps_per_turn as f32).round() as usize).min(i);
+
+                    self.set_direction(!direction).await?;
+                    for _ in 0..step_back_steps {
+                        self.pulse();
+                    }
+
+                    taken = i - step_back_steps;
+                    break;
+                }
+            }
+        }
+
+        Ok(MotorRotation {
+            turns: taken as f32 / self.steps_per_turn as f32,
+        })
+    }
+
+    async fn set_direction(&mut self, direction: MotorDirection) -> Result<(), BackendError> {
+        self.gantry.lock().unwrap().axis(self.axis).direction = direction;
+        Ok(())
+    }
+
+    fn pulse(&mut self) -> Instant {
+        self.gantry.lock().unwrap().axis(self.axis).step();
+        Instant::now()
+    }
+
+    fn delay(&mut self, _since: Instant, _interval: Duration) {}
+
+    fn steps_per_turn(&self) -> usize {
+        self.steps_per_turn
+    }
+
+    fn epsilon(&self) -> f32 {
+        0.5 / self.steps_per_turn as f32
     }
 }
 
@@ -98,24 +362,25 @@ pub struct FakeCamera;
 impl CameraBackend for FakeCamera {
     type FrameType = FakeCameraFrame;
 
-    async fn start(&mut self) -> String {
+    async fn start(&mut self) -> Result<String, BackendError> {
         println!("Start camera");
-        format!("https://cdn.mos.cms.futurecdn.net/4wpKrH93D37dDPTisdqGy4-1200-80.jpg")
+        Ok("https://cdn.mos.cms.futurecdn.net/4wpKrH93D37dDPTisdqGy4-1200-80.jpg".to_string())
     }
 
     fn capture(&mut self) -> FakeCameraFrame {
         FakeCameraFrame
     }
 
-    async fn stop(&mut self) {
+    async fn stop(&mut self) -> Result<(), BackendError> {
         println!("Stop camera");
+        Ok(())
     }
 }
 
 pub struct FakeCameraFrame;
 
 impl CameraFrame for FakeCameraFrame {
-    async fn take(self) -> Option<Vec<u8>> {
-        Some(Vec::new())
+    async fn take(self) -> Result<Vec<u8>, BackendError> {
+        Ok(Vec::new())
     }
 }
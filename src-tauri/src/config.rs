@@ -0,0 +1,46 @@
+use std::{env, io, path::PathBuf};
+
+use directories_next::ProjectDirs;
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("failed to read config file {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("invalid config file {}: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+}
+
+/// Load a TOML config file named `file_name` from the config directory, or from the path in the
+/// `env_var` environment variable when it's set.
+///
+/// Falls back to the default when there's no file in the config directory, a path given through
+/// `env_var` has to exist.
+pub fn load<T: DeserializeOwned + Default>(
+    file_name: &str,
+    env_var: &str,
+) -> Result<T, ConfigError> {
+    let path = match env::var(env_var) {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => {
+            let proj_dir = ProjectDirs::from("io.github", "pantae35872", "inert")
+                .expect("No config directory");
+            let path = proj_dir.config_dir().join(file_name);
+            if !path.exists() {
+                return Ok(T::default());
+            }
+            path
+        }
+    };
+
+    let config = std::fs::read_to_string(&path).map_err(|source| ConfigError::Io {
+        path: path.clone(),
+        source,
+    })?;
+
+    toml::from_str(&config).map_err(|source| ConfigError::Parse { path, source })
+}
@@ -0,0 +1,58 @@
+use std::{io, path::PathBuf};
+
+use directories_next::ProjectDirs;
+use serde::{Serialize, de::DeserializeOwned};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum DataFileError {
+    #[error("failed to access {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("invalid {}: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+}
+
+/// Path of the file named `file_name` in the data directory the app keeps its state in
+pub fn path(file_name: &str) -> PathBuf {
+    let proj_dir =
+        ProjectDirs::from("io.github", "pantae35872", "inert").expect("No data directory");
+
+    proj_dir.data_dir().join(file_name)
+}
+
+/// Load the JSON file named `file_name` from the data directory, none if it was never saved
+pub async fn load<T: DeserializeOwned>(file_name: &str) -> Result<Option<T>, DataFileError> {
+    let path = path(file_name);
+    let data = match tokio::fs::read_to_string(&path).await {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(source) => return Err(DataFileError::Io { path, source }),
+    };
+
+    serde_json::from_str(&data)
+        .map(Some)
+        .map_err(|source| DataFileError::Parse { path, source })
+}
+
+/// Save `data` as the JSON file named `file_name` in the data directory
+pub async fn save<T: Serialize>(file_name: &str, data: &T) -> Result<(), DataFileError> {
+    let path = path(file_name);
+    let data = serde_json::to_string_pretty(data).expect("Serialization failed");
+
+    tokio::fs::write(&path, data)
+        .await
+        .map_err(|source| DataFileError::Io { path, source })
+}
+
+/// Remove the file named `file_name` from the data directory, it's fine if there's none
+pub async fn clear(file_name: &str) -> Result<(), DataFileError> {
+    let path = path(file_name);
+    match tokio::fs::remove_file(&path).await {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(source) => Err(DataFileError::Io { path, source }),
+    }
+}
@@ -0,0 +1,188 @@
+use jpeg_decoder::{Decoder, ImageInfo, PixelFormat};
+use thiserror::Error;
+
+/// Size of the grayscale thumbnails frames are compared by in [`difference`]
+const THUMBNAIL_WIDTH: usize = 32;
+const THUMBNAIL_HEIGHT: usize = 24;
+
+#[derive(Debug, Error)]
+pub enum FrameError {
+    #[error("failed to decode the camera frame: {0}")]
+    Decode(#[from] jpeg_decoder::Error),
+    #[error("camera frames in {0:?} aren't supported")]
+    PixelFormat(PixelFormat),
+    #[error("the camera frame is empty")]
+    Empty,
+    #[error("the camera frame has {got} bytes of pixels instead of {expected}")]
+    Truncated { expected: usize, got: usize },
+}
+
+/// How much two JPEG frames differ, the mean brightness difference between 0 and 1
+pub fn difference(a: &[u8], b: &[u8]) -> Result<f32, FrameError> {
+    let a = thumbnail(a, THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT)?;
+    let b = thumbnail(b, THUMBNAIL_WIDTH, THUMBNAIL_HEIGHT)?;
+    let total = a
+        .iter()
+        .zip(&b)
+        .map(|(a, b)| a.abs_diff(*b) as u32)
+        .sum::<u32>();
+
+    Ok(total as f32 / (a.len() as f32 * u8::MAX as f32))
+}
+
+/// Difference hash of a JPEG frame, frames that look alike have hashes that differ in few bits
+///
+/// Each bit is whether a pixel of a 9 by 8 grayscale thumbnail is darker than the one to its
+/// right, so it doesn't change with the brightness or size of the image.
+pub fn perceptual_hash(jpeg: &[u8]) -> Result<u64, FrameError> {
+    let pixels = thumbnail(jpeg, 9, 8)?;
+
+    Ok(pixels
+        .chunks_exact(9)
+        .flat_map(|row| row.windows(2).map(|pair| pair[0] < pair[1]))
+        .fold(0, |hash, bit| hash << 1 | bit as u64))
+}
+
+/// How many bits two [`perceptual_hash`]es differ in, from 0 to 64
+pub fn hash_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Decode the frame at the smallest size that's still at least `width` by `height`
+///
+/// The pixels are checked to be all there, so they can be indexed by the returned size.
+pub fn decode(
+    jpeg: &[u8],
+    width: usize,
+    height: usize,
+) -> Result<(Vec<u8>, ImageInfo), FrameError> {
+    let mut decoder = Decoder::new(jpeg);
+    // Decoding at a fraction of the size is a lot faster on the Pi
+    decoder.scale(width as u16, height as u16)?;
+    let pixels = decoder.decode()?;
+    let info = decoder.info().expect("decoded frame has no info");
+
+    check(&pixels, &info)?;
+    Ok((pixels, info))
+}
+
+/// Make sure there's a pixel in `pixels` for every pixel `info` says the frame has
+fn check(pixels: &[u8], info: &ImageInfo) -> Result<(), FrameError> {
+    if info.width == 0 || info.height == 0 {
+        return Err(FrameError::Empty);
+    }
+    let expected = info.width as usize * info.height as usize * info.pixel_format.pixel_bytes();
+    if pixels.len() < expected {
+        return Err(FrameError::Truncated {
+            expected,
+            got: pixels.len(),
+        });
+    }
+
+    Ok(())
+}
+
+/// The frame shrunk to a grayscale `thumbnail_width` by `thumbnail_height` image, row by row
+fn thumbnail(
+    jpeg: &[u8],
+    thumbnail_width: usize,
+    thumbnail_height: usize,
+) -> Result<Vec<u8>, FrameError> {
+    let (pixels, info) = decode(jpeg, thumbnail_width, thumbnail_height)?;
+
+    let luma = |pixel: &[u8]| match info.pixel_format {
+        PixelFormat::L8 => Ok(pixel[0]),
+        // Big endian, the low byte doesn't matter at this size
+        PixelFormat::L16 => Ok(pixel[0]),
+        PixelFormat::RGB24 => Ok(((pixel[0] as u32 * 299
+            + pixel[1] as u32 * 587
+            + pixel[2] as u32 * 114)
+            / 1000) as u8),
+        format => Err(FrameError::PixelFormat(format)),
+    };
+
+    let (width, height) = (info.width as usize, info.height as usize);
+    let pixel_bytes = info.pixel_format.pixel_bytes();
+    (0..thumbnail_height)
+        .flat_map(|y| (0..thumbnail_width).map(move |x| (x, y)))
+        .map(|(x, y)| {
+            let (x, y) = (x * width / thumbnail_width, y * height / thumbnail_height);
+            let at = (y * width + x) * pixel_bytes;
+            luma(&pixels[at..at + pixel_bytes])
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use jpeg_decoder::CodingProcess;
+
+    use super::*;
+
+    /// Black and white squares, and the same with black and white swapped
+    const CHECKERBOARD: &[u8] = include_bytes!("../fixtures/checkerboard.jpeg");
+    const CHECKERBOARD_INVERTED: &[u8] = include_bytes!("../fixtures/checkerboard_inverted.jpeg");
+    /// Blocks of different grays, and the same a lot brighter with less contrast
+    const BLOCKS: &[u8] = include_bytes!("../fixtures/blocks.jpeg");
+    const BLOCKS_BRIGHT: &[u8] = include_bytes!("../fixtures/blocks_bright.jpeg");
+
+    #[test]
+    fn identical_frames_dont_differ() {
+        assert_eq!(difference(CHECKERBOARD, CHECKERBOARD).unwrap(), 0.0);
+        assert_eq!(difference(BLOCKS, BLOCKS).unwrap(), 0.0);
+    }
+
+    #[test]
+    fn an_inverted_frame_differs_completely() {
+        let difference = difference(CHECKERBOARD, CHECKERBOARD_INVERTED).unwrap();
+        assert!(difference > 0.95, "{difference}");
+    }
+
+    #[test]
+    fn hash_ignores_brightness() {
+        let blocks = perceptual_hash(BLOCKS).unwrap();
+        let bright = perceptual_hash(BLOCKS_BRIGHT).unwrap();
+        assert!(hash_distance(blocks, bright) <= 2);
+
+        // But not what the frame shows
+        let checkerboard = perceptual_hash(CHECKERBOARD).unwrap();
+        assert!(hash_distance(blocks, checkerboard) > 10);
+    }
+
+    #[test]
+    fn rejects_broken_frames() {
+        assert!(matches!(
+            difference(&[], CHECKERBOARD),
+            Err(FrameError::Decode(_))
+        ));
+        let truncated = &CHECKERBOARD[..CHECKERBOARD.len() / 2];
+        assert!(matches!(
+            perceptual_hash(truncated),
+            Err(FrameError::Decode(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_missing_pixels() {
+        let info = |width, height| ImageInfo {
+            width,
+            height,
+            pixel_format: PixelFormat::RGB24,
+            coding_process: CodingProcess::DctSequential,
+        };
+
+        assert!(matches!(check(&[], &info(0, 4)), Err(FrameError::Empty)));
+        assert!(matches!(
+            check(&[0; 12], &info(4, 0)),
+            Err(FrameError::Empty)
+        ));
+        assert!(matches!(
+            check(&[0; 20], &info(4, 2)),
+            Err(FrameError::Truncated {
+                expected: 24,
+                got: 20
+            })
+        ));
+        assert!(check(&[0; 24], &info(4, 2)).is_ok());
+    }
+}
@@ -1,44 +1,197 @@
-use std::{sync::Arc, time::Duration};
+use std::{io, path::PathBuf, sync::Arc, time::Duration};
 use ts_rs::TS;
 
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 use tokio::sync::{Mutex, MutexGuard};
 
 use crate::{
-    backend::{ActuatorBackend, Backend, CameraBackend, CameraFrame, MagnetBackend},
-    inventory::{allocator::ItemAllocator, db::Database},
-    plane::{Plane, PlaneImpl},
+    backend::{
+        ActuatorBackend, Backend, BackendError, CameraBackend, CameraFrame, MagnetBackend,
+        MotionProfile,
+    },
+    config::ConfigError,
+    data_file::DataFileError,
+    frame::FrameError,
+    inventory::{
+        allocator::{AllocatorConfig, AllocatorError, ItemAllocator},
+        db::{
+            Database,
+            sqlite::{MigrationError, StoredItem},
+        },
+        fetched::FetchedItem,
+        history::NewEvent,
+        verification::VerificationConfig,
+    },
+    plane::{PlaneError, PlaneImpl},
 };
 
-mod allocator;
+pub(crate) mod allocator;
+mod compaction;
 mod db;
-
-#[derive(Debug)]
-struct InventoryData {
-    db: Database,
-    allocator: ItemAllocator,
-}
-
+mod fetched;
+mod history;
+mod planner;
+mod similarity;
+mod verification;
+
+pub use allocator::PlacementHeuristic;
+pub use history::{EventFilter, EventKind, InventoryEvent};
+pub use planner::{RetrievalPlan, plan_order};
+pub use similarity::SimilarItem;
+
+/// Pause after picking up a new item before carrying it off, so it stops swinging
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// Items stored on the plane
+///
+/// The database can be read at any time, anything that moves the head goes through an
+/// [`InventoryImpl`] which holds the plane for as long as it lives.
 #[derive(Debug)]
 pub struct Inventory {
-    data: Mutex<InventoryData>,
+    db: Database,
+    allocator: Mutex<ItemAllocator>,
+    allocator_config: AllocatorConfig,
+    verification_config: VerificationConfig,
+    /// Where the access point is on the plane as last measured
+    access_point: Mutex<(usize, usize)>,
+    /// Item brought to the access point that hasn't been returned yet, kept in `fetched.json`
+    fetched: Mutex<Option<i64>>,
 }
 
 impl Inventory {
-    pub async fn new(plane: &PlaneImpl<'_>) -> Self {
-        let db = Database::new().await;
-        let allocator = ItemAllocator::new(&db, plane.width(), plane.height()).await;
-        let data = Mutex::new(InventoryData { db, allocator });
+    pub async fn new(plane: &PlaneImpl<'_>) -> Result<Self, InventoryError> {
+        let allocator_config: AllocatorConfig =
+            crate::config::load("allocator.toml", "INERT_ALLOCATOR_CONFIG")?;
+        let verification_config =
+            crate::config::load("verification.toml", "INERT_VERIFICATION_CONFIG")?;
+        let db = Database::new().await?;
+        let allocator =
+            ItemAllocator::new(&db, plane.width(), plane.height(), &allocator_config).await;
+        let fetched = FetchedItem::load().await.unwrap_or_else(|err| {
+            println!("Assuming nothing is at the access point: {err}");
+            None
+        });
+        if let Some(id) = fetched {
+            println!("Item {id} is still at the access point, it has to be returned first");
+        }
 
-        Self { data }
+        Ok(Self {
+            db,
+            allocator: Mutex::new(allocator),
+            allocator_config,
+            verification_config,
+            access_point: Mutex::new(access_point(plane.width(), plane.height())),
+            fetched: Mutex::new(fetched),
+        })
     }
 
-    pub async fn get<'a>(&'a self, backend: Arc<Backend>, plane: &'a Plane) -> InventoryImpl<'a> {
+    /// Rebuild the free space after the plane got measured again
+    pub async fn resize(&self, plane: &PlaneImpl<'_>) {
+        let mut allocator = self.allocator.lock().await;
+        let heuristic = allocator.heuristic();
+        *allocator = ItemAllocator::new(
+            &self.db,
+            plane.width(),
+            plane.height(),
+            &self.allocator_config,
+        )
+        .await;
+        allocator.set_heuristic(heuristic);
+
+        *self.access_point.lock().await = access_point(plane.width(), plane.height());
+    }
+
+    /// Lock the inventory for an operation that moves the head, the plane has to be locked first
+    pub async fn get<'a>(
+        &'a self,
+        backend: Arc<Backend>,
+        plane: PlaneImpl<'a>,
+    ) -> InventoryImpl<'a> {
         InventoryImpl {
             backend,
             plane,
-            data: self.data.lock().await,
+            db: &self.db,
+            allocator: self.allocator.lock().await,
+            fetched: self.fetched.lock().await,
+            verification: &self.verification_config,
+        }
+    }
+
+    pub async fn list_items(&self) -> Vec<DisplayItem> {
+        self.db
+            .list_all_items()
+            .await
+            .iter()
+            .map(|result| DisplayItem {
+                id: result.id,
+                image_path: result.image_id.clone(),
+                display_name: result.display_name.clone(),
+                amount: result.amount,
+            })
+            .collect()
+    }
+
+    pub async fn access_point(&self) -> (usize, usize) {
+        *self.access_point.lock().await
+    }
+
+    /// Plan fetching every item in `ids` with the head starting at `start`, or at the access point
+    pub async fn plan_retrieval(
+        &self,
+        backend: &Backend,
+        start: Option<(usize, usize)>,
+        ids: &[i64],
+    ) -> Result<RetrievalPlan, InventoryError> {
+        let mut slots = Vec::with_capacity(ids.len());
+        for &id in ids {
+            let slot = self.slot(id).await.ok_or(InventoryError::NoSuchItem(id))?;
+            slots.push((id, slot.pick_point()));
         }
+
+        let access_point = self.access_point().await;
+        Ok(RetrievalPlan::new(backend, start.unwrap_or(access_point), access_point, slots).await)
+    }
+
+    /// Where the item `id` is stored, none if there's no such item
+    pub async fn slot(&self, id: i64) -> Option<Rectangle> {
+        self.db.find_item_by_id(id).await.map(|item| item.rect)
+    }
+
+    /// How much is left of the item `id`, none if there's no such item
+    pub async fn amount(&self, id: i64) -> Option<u64> {
+        self.db.find_item_by_id(id).await.map(|item| item.amount)
+    }
+
+    /// The item called `name` with the most left, none if there's no such item
+    pub async fn find_by_name(&self, name: &str) -> Option<i64> {
+        self.db
+            .find_item_by_name(name)
+            .await
+            .into_iter()
+            .max_by_key(|item| item.amount)
+            .map(|item| item.id)
+    }
+
+    /// Operation history matching `filter`, newest first
+    pub async fn list_events(
+        &self,
+        filter: EventFilter,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<InventoryEvent> {
+        self.db.list_events(filter, offset, limit).await
+    }
+
+    /// Change how new items are placed, items that are already stored stay where they are
+    pub async fn set_heuristic(&self, heuristic: PlacementHeuristic) {
+        self.allocator.lock().await.set_heuristic(heuristic);
+    }
+
+    /// Reserve a free slot for a new item
+    pub async fn allocate(&self, size: ItemSize) -> Option<Rectangle> {
+        let (width, height) = size.dimensions();
+        self.allocator.lock().await.allocate(width, height)
     }
 }
 
@@ -51,82 +204,348 @@ pub struct DisplayItem {
     display_name: String,
 }
 
+#[derive(Debug, Error)]
+pub enum InventoryError {
+    #[error(transparent)]
+    Backend(#[from] BackendError),
+    #[error(transparent)]
+    Plane(#[from] PlaneError),
+    #[error(transparent)]
+    Allocator(#[from] AllocatorError),
+    #[error("there's no item {0}")]
+    NoSuchItem(i64),
+    #[error("item {0} is still at the access point, return it first")]
+    ItemOut(i64),
+    #[error("item {0} isn't at the access point")]
+    NotFetched(i64),
+    #[error("only {available} left, can't take {requested}")]
+    NotEnough { available: u64, requested: usize },
+    #[error("nothing was picked up at {0:?}")]
+    PickMissed((usize, usize)),
+    #[error(transparent)]
+    Frame(#[from] FrameError),
+    #[error(transparent)]
+    Config(#[from] ConfigError),
+    #[error(transparent)]
+    Migration(#[from] MigrationError),
+    #[error("failed to access {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("invalid {}: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+}
+
+impl From<DataFileError> for InventoryError {
+    fn from(err: DataFileError) -> Self {
+        match err {
+            DataFileError::Io { path, source } => Self::Io { path, source },
+            DataFileError::Parse { path, source } => Self::Parse { path, source },
+        }
+    }
+}
+
 pub struct InventoryImpl<'a> {
     backend: Arc<Backend>,
-    plane: &'a Plane,
-    data: MutexGuard<'a, InventoryData>,
+    plane: PlaneImpl<'a>,
+    db: &'a Database,
+    allocator: MutexGuard<'a, ItemAllocator>,
+    fetched: MutexGuard<'a, Option<i64>>,
+    verification: &'a VerificationConfig,
 }
 
 impl<'a> InventoryImpl<'a> {
-    pub async fn remove_item(&mut self, id: i64) {
-        let item = self.data.db.find_item_by_id(id).await;
-
-        let mut plane = self.plane.get(Arc::clone(&self.backend)).await;
-        let mut actuator = self.backend.actuator().await;
-        let mut magnet = self.backend.magnet().await;
+    /// Bring the item to the access point for good and forget it
+    pub async fn remove_item(&mut self, id: i64) -> Result<(), InventoryError> {
+        self.ensure_access_point_free()?;
+        self.resume_compaction().await?;
+        let item = self
+            .db
+            .find_item_by_id(id)
+            .await
+            .ok_or(InventoryError::NoSuchItem(id))?;
 
-        plane.move_to(item.rect.x, item.rect.y).await;
+        let result = async {
+            self.pick_up(item.rect.pick_point()).await?;
+            self.put_down(self.access_point()).await?;
+            self.backend.actuator().await.contract().await?;
 
-        actuator.extend().await;
-        magnet.set(true).await;
-        actuator.contract().await;
+            self.db.remove_item_by_id(id).await;
+            self.allocator.deallocate(item.rect)?;
+            Ok(())
+        }
+        .await;
+
+        self.record(
+            EventKind::Remove,
+            (Some(item.id), &item.name),
+            item.rect,
+            item.amount,
+            &result,
+        )
+        .await;
+        result
+    }
 
-        plane.move_to(plane.width(), plane.height() - 10).await;
-        actuator.extend().await;
-        magnet.set(false).await;
-        actuator.contract().await;
+    /// Bring the item to the access point so the user can take from or restock it, it keeps its
+    /// slot until it's returned with [`Self::return_item`]
+    pub async fn fetch_item(&mut self, id: i64) -> Result<(), InventoryError> {
+        self.ensure_access_point_free()?;
+        self.resume_compaction().await?;
+        let item = self
+            .db
+            .find_item_by_id(id)
+            .await
+            .ok_or(InventoryError::NoSuchItem(id))?;
 
-        self.data.db.remove_item_by_id(id).await;
-        self.data.allocator.deallocate(item.rect);
+        self.pick_up(item.rect.pick_point()).await?;
+        self.put_down(self.access_point()).await?;
+        self.set_fetched(Some(id)).await?;
+        self.backend.actuator().await.contract().await?;
+        Ok(())
     }
 
-    pub async fn list_items(&mut self) -> Vec<DisplayItem> {
-        self.data
+    /// Update the amount of the fetched item and put it back in its slot
+    ///
+    /// The item is removed and left at the access point instead when `remove` is set or when
+    /// nothing is left of it.
+    pub async fn return_item(
+        &mut self,
+        id: i64,
+        change: QuantityChange,
+        remove: bool,
+    ) -> Result<(), InventoryError> {
+        if *self.fetched != Some(id) {
+            return Err(InventoryError::NotFetched(id));
+        }
+        let item = self
             .db
-            .list_all_items()
+            .find_item_by_id(id)
             .await
-            .iter()
-            .map(|result| DisplayItem {
-                id: result.id,
-                image_path: result.image_id.clone(),
-                display_name: result.display_name.clone(),
-                amount: result.amount,
-            })
-            .collect()
+            .ok_or(InventoryError::NoSuchItem(id))?;
+
+        let left = match change {
+            QuantityChange::Unchanged => Ok(item.amount),
+            QuantityChange::Take { amount } => {
+                item.amount
+                    .checked_sub(amount as u64)
+                    .ok_or(InventoryError::NotEnough {
+                        available: item.amount,
+                        requested: amount,
+                    })
+            }
+            QuantityChange::Restock { amount } => Ok(item.amount + amount as u64),
+        };
+        let result = match left {
+            Ok(left) => self.put_back(&item, left, remove).await,
+            Err(err) => Err(err),
+        };
+
+        match change {
+            QuantityChange::Unchanged => {}
+            QuantityChange::Take { amount } => {
+                self.record(
+                    EventKind::Take,
+                    (Some(item.id), &item.name),
+                    item.rect,
+                    amount as u64,
+                    &result,
+                )
+                .await
+            }
+            QuantityChange::Restock { amount } => {
+                self.record(
+                    EventKind::Restock,
+                    (Some(item.id), &item.name),
+                    item.rect,
+                    amount as u64,
+                    &result,
+                )
+                .await
+            }
+        }
+        result
+    }
+
+    /// Put the fetched item back with `left` in it, or remove it if told to or if it's empty
+    async fn put_back(
+        &mut self,
+        item: &StoredItem<Item>,
+        left: u64,
+        remove: bool,
+    ) -> Result<(), InventoryError> {
+        if remove || left == 0 {
+            self.db.remove_item_by_id(item.id).await;
+
+            let cleared = self.set_fetched(None).await;
+            let result = self
+                .allocator
+                .deallocate(item.rect)
+                .map_err(Into::into)
+                .and(cleared);
+            self.record(
+                EventKind::Remove,
+                (Some(item.id), &item.name),
+                item.rect,
+                left,
+                &result,
+            )
+            .await;
+            return result;
+        }
+
+        self.place_item(item.rect).await?;
+        self.db.set_amount(item.id, left).await;
+        self.set_fetched(None).await?;
+        self.backend.actuator().await.contract().await?;
+        Ok(())
+    }
+
+    /// Move the head to where new items are put down by the user
+    pub async fn move_to_access_point(&mut self) -> Result<(), InventoryError> {
+        let (x, y) = self.access_point();
+        self.plane.move_to(x, y, MotionProfile::fast()).await?;
+
+        Ok(())
+    }
+
+    pub async fn add_item(
+        &mut self,
+        name: impl AsRef<str>,
+        rect: Rectangle,
+        amount: usize,
+    ) -> Result<(), InventoryError> {
+        let frame = match self.take_in(rect).await {
+            Ok(frame) => frame,
+            Err(err) => {
+                // The item never made it into `rect`, so the slot is free again
+                if let Err(err) = self.allocator.deallocate(rect) {
+                    println!("Failed to free the slot of an item that wasn't added: {err}");
+                }
+
+                let result = Err(err);
+                self.record(
+                    EventKind::Add,
+                    (None, name.as_ref()),
+                    rect,
+                    amount as u64,
+                    &result,
+                )
+                .await;
+                return result;
+            }
+        };
+
+        let id = self.db.add_item(name.as_ref(), amount, rect, frame).await;
+        let result = self
+            .backend
+            .actuator()
+            .await
+            .contract()
+            .await
+            .map_err(Into::into);
+
+        self.record(
+            EventKind::Add,
+            (Some(id), name.as_ref()),
+            rect,
+            amount as u64,
+            &result,
+        )
+        .await;
+        result
     }
 
-    pub async fn prepare_add_item(&mut self) -> Option<Rectangle> {
-        let mut plane = self.plane.get(Arc::clone(&self.backend)).await;
+    /// Photograph the new item at the access point and carry it into `rect`, leaves the actuator
+    /// extended
+    async fn take_in(&mut self, rect: Rectangle) -> Result<Vec<u8>, InventoryError> {
+        self.ensure_access_point_free()?;
+        self.resume_compaction().await?;
+
+        let frame = self.capture().await?;
 
-        plane.move_to(plane.width(), plane.height() - 10).await;
+        self.place_item(rect).await?;
+        Ok(frame)
+    }
 
-        self.data.allocator.allocate(22, 22)
+    /// Carry the item at the access point into `rect`, leaves the actuator extended
+    async fn place_item(&mut self, rect: Rectangle) -> Result<(), InventoryError> {
+        self.pick_up(self.access_point()).await?;
+        tokio::time::sleep(SETTLE_TIME).await;
+        self.put_down(rect.pick_point()).await
+    }
+
+    /// Move to `x`, `y` and lift whatever is there, leaves the actuator contracted and the magnet on
+    async fn pick_up(&mut self, (x, y): (usize, usize)) -> Result<(), InventoryError> {
+        self.plane.move_to(x, y, MotionProfile::fast()).await?;
+
+        if self.verification.enabled {
+            self.lift_verified((x, y)).await
+        } else {
+            self.lift().await
+        }
     }
 
-    pub async fn add_item(&mut self, name: impl AsRef<str>, rect: Rectangle, amount: usize) {
-        let mut plane = self.plane.get(Arc::clone(&self.backend)).await;
+    /// Grab whatever is under the head and lift it, leaves the actuator contracted and the magnet
+    /// on
+    async fn lift(&mut self) -> Result<(), InventoryError> {
         let mut actuator = self.backend.actuator().await;
         let mut magnet = self.backend.magnet().await;
+        actuator.extend().await?;
+        magnet.set(true).await?;
+        actuator.contract().await?;
+        Ok(())
+    }
 
-        let frame = self.backend.camera().await.capture();
-        if let Some(frame) = frame.take().await {
-            self.data.db.add_item(name, amount, rect, frame).await;
-        }
+    /// Carry the item on the head to `x`, `y` and let go of it, leaves the actuator extended
+    async fn put_down(&mut self, (x, y): (usize, usize)) -> Result<(), InventoryError> {
+        self.plane.move_to(x, y, MotionProfile::gentle()).await?;
+
+        let mut actuator = self.backend.actuator().await;
+        let mut magnet = self.backend.magnet().await;
+        actuator.extend().await?;
+        magnet.set(false).await?;
+        Ok(())
+    }
 
-        actuator.extend().await;
-        magnet.set(true).await;
-        actuator.contract().await;
+    fn access_point(&self) -> (usize, usize) {
+        access_point(self.plane.width(), self.plane.height())
+    }
 
-        tokio::time::sleep(Duration::from_secs(1)).await;
+    async fn capture(&self) -> Result<Vec<u8>, BackendError> {
+        let frame = self.backend.camera().await.capture();
+        frame.take().await
+    }
 
-        plane.move_to(rect.x, rect.y).await;
+    /// Add the outcome of an operation on the item `id` called `name` to the history, the id is
+    /// none when adding the item failed before it got one
+    async fn record<T>(
+        &self,
+        kind: EventKind,
+        item: (Option<i64>, &str),
+        rect: Rectangle,
+        amount: u64,
+        result: &Result<T, InventoryError>,
+    ) {
+        let event = NewEvent::new(kind, item, rect, amount, result);
+        self.db.record_event(event).await;
+    }
 
-        actuator.extend().await;
-        magnet.set(false).await;
-        actuator.contract().await;
+    /// Fail while a fetched item is still taking up the access point
+    fn ensure_access_point_free(&self) -> Result<(), InventoryError> {
+        match *self.fetched {
+            Some(id) => Err(InventoryError::ItemOut(id)),
+            None => Ok(()),
+        }
     }
 }
 
+/// Where the user puts down new items and picks up removed ones on a plane of `width` by `height`
+fn access_point(width: usize, height: usize) -> (usize, usize) {
+    (width, height - 30)
+}
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Item {
     pub rect: Rectangle,
@@ -135,7 +554,8 @@ pub struct Item {
     pub display_name: String,
 }
 
-#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, TS)]
+/// Area on the plane, in millimetres
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
 #[ts(export)]
 pub struct Rectangle {
     x: usize,
@@ -144,6 +564,56 @@ pub struct Rectangle {
     height: usize,
 }
 
+impl Rectangle {
+    /// Where the head picks up and puts down the item stored in this rectangle
+    ///
+    /// The corner closest to home, where items have always been put down. Moving it would leave
+    /// the items already stored out of reach of the head.
+    pub fn pick_point(&self) -> (usize, usize) {
+        (self.x, self.y)
+    }
+}
+
+/// What the user did with a fetched item before it's returned
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub enum QuantityChange {
+    Unchanged,
+    /// Took `amount` out of the container
+    Take {
+        amount: usize,
+    },
+    /// Put `amount` more into the container
+    Restock {
+        amount: usize,
+    },
+}
+
+/// Footprint of an item, picked by the user when adding it
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub enum ItemSize {
+    /// 40x40mm, screw boxes and the like
+    Small,
+    /// 60x60mm
+    Medium,
+    /// 100x100mm, trays
+    Large,
+    /// Anything else, in millimetres
+    Custom { width: usize, height: usize },
+}
+
+impl ItemSize {
+    pub fn dimensions(self) -> (usize, usize) {
+        match self {
+            Self::Small => (40, 40),
+            Self::Medium => (60, 60),
+            Self::Large => (100, 100),
+            Self::Custom { width, height } => (width, height),
+        }
+    }
+}
+
 #[cfg(feature = "visualization")]
 pub mod visualizer {
     use serde::{Deserialize, Serialize};
@@ -1,233 +1,579 @@
-use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use ts_rs::TS;
 
-use crate::inventory::{Rectangle, db::Database};
+use crate::inventory::{Rectangle, access_point, db::Database};
 
 #[cfg(feature = "visualization")]
 pub mod visualizer;
 
+/// Keeps track of the free space on the plane
+///
+/// The free space is stored as the set of maximal free rectangles: every free rectangle that
+/// can't grow in any direction without hitting an item or the edge of the plane. They overlap each
+/// other, and any free area an item fits in is inside at least one of them. The set only depends
+/// on which items are stored, so it's always rebuilt from `used` instead of being patched.
 #[derive(Debug)]
 pub struct ItemAllocator {
+    width: usize,
+    height: usize,
+    /// Areas no item may be placed in
+    keep_out: Vec<Rectangle>,
+    /// Minimum distance between two items
+    gap: usize,
+
     free_list: Vec<Rectangle>,
+    used: Vec<Rectangle>,
+
+    heuristic: PlacementHeuristic,
+}
+
+/// Where items may be placed, read from `allocator.toml`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AllocatorConfig {
+    /// Minimum distance between two items, in millimetres
+    pub gap_mm: usize,
+    /// How far from the access point items are kept, in millimetres
+    pub access_point_clearance_mm: usize,
+    /// Other areas no item may be placed in, like mechanical obstructions
+    pub keep_out: Vec<Rectangle>,
+}
+
+impl Default for AllocatorConfig {
+    fn default() -> Self {
+        Self {
+            gap_mm: 5,
+            access_point_clearance_mm: 60,
+            keep_out: Vec::new(),
+        }
+    }
+}
+
+/// How [`ItemAllocator::allocate`] picks between the free rectangles an item fits in, the item
+/// always goes in the corner of the rectangle closest to home
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub enum PlacementHeuristic {
+    /// Leave as little as possible on the tighter side, packs the densest in general
+    #[default]
+    BestShortSideFit,
+    /// Leave as little area as possible
+    BestAreaFit,
+    /// Keep the items as close to home as possible, row by row
+    BottomLeft,
+}
+
+impl PlacementHeuristic {
+    /// Lower is better
+    fn score(self, free: &Rectangle, width: usize, height: usize) -> (usize, usize) {
+        let leftover_width = free.width - width;
+        let leftover_height = free.height - height;
+
+        match self {
+            Self::BestShortSideFit => (
+                leftover_width.min(leftover_height),
+                leftover_width.max(leftover_height),
+            ),
+            Self::BestAreaFit => (
+                free.width * free.height - width * height,
+                leftover_width.min(leftover_height),
+            ),
+            Self::BottomLeft => (free.y + height, free.x),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum AllocatorError {
+    #[error("{0:?} isn't allocated")]
+    NotAllocated(Rectangle),
+    #[error("{0:?} isn't free")]
+    Occupied(Rectangle),
 }
 
 impl ItemAllocator {
-    pub async fn new(db: &Database, width: usize, height: usize) -> Self {
-        let mut free_list = vec![Rectangle {
-            x: 0,
-            y: 0,
+    pub async fn new(db: &Database, width: usize, height: usize, config: &AllocatorConfig) -> Self {
+        let items = db.list_all_items().await;
+        let allocator = Self::with_items(
             width,
             height,
-        }];
-
-        let items = db.list_all_items().await;
+            config,
+            items.iter().map(|item| item.data.rect).collect(),
+        );
 
         for item in &items {
-            let mut new_list = Vec::new();
-
-            for space in free_list.drain(..) {
-                let mut split = subtract_item(&space, &item.data.rect);
-                new_list.append(&mut split);
+            if allocator
+                .keep_out
+                .iter()
+                .any(|zone| overlaps(zone, &item.rect))
+            {
+                println!(
+                    "Warning: item {} at {:?} is inside a keep-out zone",
+                    item.id, item.rect
+                );
             }
-
-            free_list = new_list;
         }
 
         #[cfg(feature = "visualization")]
         {
             super::visualizer::visualize(
                 items.iter().map(|e| e.clone().into_inner()).collect(),
-                free_list.clone(),
+                allocator.free_list.clone(),
             );
         }
 
-        Self { free_list }
+        allocator
     }
 
-    pub fn deallocate(&mut self, rect: Rectangle) {
-        // TODO: VERIFY?
-        self.free_list.insert(0, rect.into());
+    /// Allocator for a plane with the items at `used`, without a database to load them from
+    pub fn with_items(
+        width: usize,
+        height: usize,
+        config: &AllocatorConfig,
+        used: Vec<Rectangle>,
+    ) -> Self {
+        let (access_x, access_y) = access_point(width, height);
+        let access_zone = expand(
+            &Rectangle {
+                x: access_x,
+                y: access_y,
+                width: 0,
+                height: 0,
+            },
+            config.access_point_clearance_mm,
+        );
+
+        let mut allocator = Self {
+            width,
+            height,
+            keep_out: config
+                .keep_out
+                .iter()
+                .copied()
+                .chain([access_zone])
+                .collect(),
+            gap: config.gap_mm,
+            free_list: Vec::new(),
+            used,
+            heuristic: PlacementHeuristic::default(),
+        };
+        allocator.rebuild();
+
+        allocator
     }
 
-    /// Allocate a new area and returns the x y position
+    /// Free the area of a removed item
+    pub fn deallocate(&mut self, rect: Rectangle) -> Result<(), AllocatorError> {
+        let index = self
+            .used
+            .iter()
+            .position(|used| *used == rect)
+            .ok_or(AllocatorError::NotAllocated(rect))?;
+        self.used.swap_remove(index);
+
+        // Only happens with items stored before the allocator checked for overlaps, the overlap
+        // stays occupied since the free space is rebuilt from the items left
+        for other in self.used.iter().filter(|used| overlaps(used, &rect)) {
+            println!("Warning: freed {rect:?} overlaps the item at {other:?}");
+        }
+
+        self.rebuild();
+        Ok(())
+    }
+
+    pub fn heuristic(&self) -> PlacementHeuristic {
+        self.heuristic
+    }
+
+    pub fn set_heuristic(&mut self, heuristic: PlacementHeuristic) {
+        self.heuristic = heuristic;
+    }
+
+    /// Allocate a new area and returns the x y position, none if it's empty or doesn't fit
     pub fn allocate(&mut self, width: usize, height: usize) -> Option<Rectangle> {
-        for free in &self.free_list {
-            for x in free.x..free.x + free.width {
-                for y in free.y..free.y + free.height {
-                    if self.is_valid_in_free_list(x, y, width, height) {
-                        let mut queue = VecDeque::new();
-                        queue.push_back(Rectangle {
-                            x,
-                            y,
-                            width,
-                            height,
-                        });
-
-                        let mut new_free = Vec::new();
-
-                        while let Some(rect) = queue.pop_front() {
-                            let mut i = 0;
-                            while i < self.free_list.len() {
-                                let space = &self.free_list[i];
-
-                                if let Some(overlap) = get_overlap(&rect, space) {
-                                    let remaining_parts = subtract_rect(&rect, &overlap);
-                                    for r in remaining_parts {
-                                        queue.push_back(r);
-                                    }
-                                    let new_space = subtract_rect(&space.clone().into(), &rect);
-
-                                    self.free_list.remove(i);
-
-                                    for free in new_space {
-                                        new_free.push(free.into());
-                                    }
-
-                                    break;
-                                } else {
-                                    i += 1;
-                                }
-                            }
-                        }
-
-                        self.free_list.extend_from_slice(&new_free);
-
-                        return Some(Rectangle {
-                            x,
-                            y,
-                            width,
-                            height,
-                        });
-                    }
-                }
-            }
+        if width == 0 || height == 0 {
+            return None;
         }
-        None
+        let rect = self.find(width, height, self.heuristic)?;
+
+        self.used.push(rect);
+        self.split_free_list(&expand(&rect, self.gap));
+        self.check_invariants();
+
+        Some(rect)
     }
 
-    fn is_valid_in_free_list(&self, x: usize, y: usize, width: usize, height: usize) -> bool {
-        let mut queue = VecDeque::new();
-        queue.push_back(Rectangle {
-            x,
-            y,
-            width,
-            height,
-        });
+    /// Where an item would be allocated with `heuristic`, without allocating it
+    pub fn find(
+        &self,
+        width: usize,
+        height: usize,
+        heuristic: PlacementHeuristic,
+    ) -> Option<Rectangle> {
+        self.free_list
+            .iter()
+            .filter(|free| free.width >= width && free.height >= height)
+            .min_by_key(|free| heuristic.score(free, width, height))
+            .map(|free| Rectangle {
+                x: free.x,
+                y: free.y,
+                width,
+                height,
+            })
+    }
 
-        while let Some(rect) = queue.pop_front() {
-            let mut found_overlap = false;
+    /// Allocate exactly `rect`, it has to keep the same distance to the other items and keep-out
+    /// zones as an allocated one
+    pub fn reserve(&mut self, rect: Rectangle) -> Result<(), AllocatorError> {
+        if !self.free_list.iter().any(|free| contains(free, &rect)) {
+            return Err(AllocatorError::Occupied(rect));
+        }
 
-            for space in &self.free_list {
-                if let Some(overlap) = get_overlap(&rect, space) {
-                    found_overlap = true;
+        self.used.push(rect);
+        self.split_free_list(&expand(&rect, self.gap));
+        self.check_invariants();
+        Ok(())
+    }
 
-                    // Subtract the overlapping part, queue the remaining regions
-                    let remaining_parts = subtract_rect(&rect, &overlap);
-                    for r in remaining_parts {
-                        queue.push_back(r);
-                    }
+    /// Forget every allocation and start over from the items in `db`
+    pub async fn reload(&mut self, db: &Database) {
+        self.used = db
+            .list_all_items()
+            .await
+            .iter()
+            .map(|item| item.data.rect)
+            .collect();
+        self.rebuild();
+    }
 
-                    break;
-                }
+    /// Recompute the free space from scratch out of the stored items
+    fn rebuild(&mut self) {
+        self.free_list = vec![Rectangle {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        }];
+
+        for zone in self.keep_out.clone() {
+            self.split_free_list(&zone);
+        }
+        for rect in self.used.clone() {
+            self.split_free_list(&expand(&rect, self.gap));
+        }
+        self.check_invariants();
+    }
+
+    /// Cut `used` out of every free rectangle it overlaps, keeping the list maximal
+    fn split_free_list(&mut self, used: &Rectangle) {
+        let mut split = Vec::new();
+
+        self.free_list.retain(|free| {
+            if !overlaps(free, used) {
+                return true;
             }
 
-            if !found_overlap {
-                return false;
+            split.extend(split_maximal(free, used));
+            false
+        });
+
+        self.free_list.extend(split);
+        self.prune_free_list();
+    }
+
+    /// Remove every free rectangle that's inside another one
+    fn prune_free_list(&mut self) {
+        let mut i = 0;
+        while i < self.free_list.len() {
+            let contained = self
+                .free_list
+                .iter()
+                .enumerate()
+                // Of two identical rectangles only the later one is kept
+                .any(|(j, other)| {
+                    j != i
+                        && contains(other, &self.free_list[i])
+                        && (self.free_list[i] != *other || j > i)
+                });
+
+            if contained {
+                self.free_list.swap_remove(i);
+            } else {
+                i += 1;
             }
         }
-
-        true
     }
-}
 
-fn subtract_item(space: &Rectangle, item: &Rectangle) -> Vec<Rectangle> {
-    let item_right = item.x + item.width;
-    let item_bottom = item.y + item.height;
-    let space_right = space.x + space.width;
-    let space_bottom = space.y + space.height;
+    /// Free rectangles stay on the plane, keep their distance to the items and keep-out zones and
+    /// are all maximal
+    fn check_invariants(&self) {
+        if !cfg!(debug_assertions) {
+            return;
+        }
 
-    // Check if they intersect
-    if item.x >= space_right
-        || item_right <= space.x
-        || item.y >= space_bottom
-        || item_bottom <= space.y
-    {
-        // No intersection, return the original space
-        return vec![space.clone()];
+        for (i, free) in self.free_list.iter().enumerate() {
+            assert!(
+                free.width > 0 && free.height > 0,
+                "empty free rectangle {free:?}"
+            );
+            assert!(
+                free.x + free.width <= self.width && free.y + free.height <= self.height,
+                "free rectangle {free:?} is off the plane"
+            );
+            assert!(
+                !self
+                    .used
+                    .iter()
+                    .any(|used| overlaps(&expand(used, self.gap), free)),
+                "free rectangle {free:?} is too close to an item"
+            );
+            assert!(
+                !self.keep_out.iter().any(|zone| overlaps(zone, free)),
+                "free rectangle {free:?} covers a keep-out zone"
+            );
+            assert!(
+                !self
+                    .free_list
+                    .iter()
+                    .enumerate()
+                    .any(|(j, other)| i != j && contains(other, free)),
+                "free rectangle {free:?} isn't maximal"
+            );
+        }
     }
-
-    return subtract_rect(space, item);
 }
 
-/// Subtracts `sub` from `rect`, returns the list of remaining rectangles
-fn subtract_rect(rect: &Rectangle, sub: &Rectangle) -> Vec<Rectangle> {
+/// The parts of `free` left and right of `used` at full height, and above and below it at full
+/// width. Unlike a guillotine cut every one of them stays maximal.
+fn split_maximal(free: &Rectangle, used: &Rectangle) -> Vec<Rectangle> {
     let mut result = Vec::new();
 
-    let rect_right = rect.x + rect.width;
-    let rect_bottom = rect.y + rect.height;
-    let sub_right = sub.x + sub.width;
-    let sub_bottom = sub.y + sub.height;
+    let free_right = free.x + free.width;
+    let free_bottom = free.y + free.height;
+    let used_right = used.x + used.width;
+    let used_bottom = used.y + used.height;
 
-    // Top
-    if sub.y > rect.y {
+    // Left
+    if used.x > free.x {
         result.push(Rectangle {
-            x: rect.x,
-            y: rect.y,
-            width: rect.width,
-            height: sub.y - rect.y,
+            x: free.x,
+            y: free.y,
+            width: used.x - free.x,
+            height: free.height,
         });
     }
 
-    // Bottom
-    if sub_bottom < rect_bottom {
+    // Right
+    if used_right < free_right {
         result.push(Rectangle {
-            x: rect.x,
-            y: sub_bottom,
-            width: rect.width,
-            height: rect_bottom - sub_bottom,
+            x: used_right,
+            y: free.y,
+            width: free_right - used_right,
+            height: free.height,
         });
     }
 
-    // Left
-    if sub.x > rect.x {
-        let top = sub.y.max(rect.y);
-        let bottom = sub_bottom.min(rect_bottom);
+    // Top
+    if used.y > free.y {
         result.push(Rectangle {
-            x: rect.x,
-            y: top,
-            width: sub.x - rect.x,
-            height: bottom - top,
+            x: free.x,
+            y: free.y,
+            width: free.width,
+            height: used.y - free.y,
         });
     }
 
-    // Right
-    if sub_right < rect_right {
-        let top = sub.y.max(rect.y);
-        let bottom = sub_bottom.min(rect_bottom);
+    // Bottom
+    if used_bottom < free_bottom {
         result.push(Rectangle {
-            x: sub_right,
-            y: top,
-            width: rect_right - sub_right,
-            height: bottom - top,
+            x: free.x,
+            y: used_bottom,
+            width: free.width,
+            height: free_bottom - used_bottom,
         });
     }
 
     result
 }
 
-fn get_overlap(a: &Rectangle, b: &Rectangle) -> Option<Rectangle> {
-    let x1 = a.x.max(b.x);
-    let y1 = a.y.max(b.y);
-    let x2 = (a.x + a.width).min(b.x + b.width);
-    let y2 = (a.y + a.height).min(b.y + b.height);
-
-    if x1 < x2 && y1 < y2 {
-        Some(Rectangle {
-            x: x1,
-            y: y1,
-            width: x2 - x1,
-            height: y2 - y1,
-        })
-    } else {
-        None
+/// `rect` grown by `margin` on every side, it stops at 0 but can go past the far edges of the
+/// plane
+fn expand(rect: &Rectangle, margin: usize) -> Rectangle {
+    let x = rect.x.saturating_sub(margin);
+    let y = rect.y.saturating_sub(margin);
+
+    Rectangle {
+        x,
+        y,
+        width: rect.x + rect.width + margin - x,
+        height: rect.y + rect.height + margin - y,
+    }
+}
+
+fn overlaps(a: &Rectangle, b: &Rectangle) -> bool {
+    a.x < b.x + b.width && b.x < a.x + a.width && a.y < b.y + b.height && b.y < a.y + a.height
+}
+
+/// Whether `inner` is entirely inside `outer`
+fn contains(outer: &Rectangle, inner: &Rectangle) -> bool {
+    inner.x >= outer.x
+        && inner.y >= outer.y
+        && inner.x + inner.width <= outer.x + outer.width
+        && inner.y + inner.height <= outer.y + outer.height
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    const WIDTH: usize = 400;
+    const HEIGHT: usize = 300;
+
+    fn config() -> AllocatorConfig {
+        AllocatorConfig {
+            gap_mm: 5,
+            access_point_clearance_mm: 40,
+            keep_out: vec![Rectangle {
+                x: 150,
+                y: 100,
+                width: 30,
+                height: 50,
+            }],
+        }
+    }
+
+    fn is_free(allocator: &ItemAllocator, rect: &Rectangle) -> bool {
+        rect.x + rect.width <= allocator.width
+            && rect.y + rect.height <= allocator.height
+            && !allocator
+                .used
+                .iter()
+                .any(|used| overlaps(&expand(used, allocator.gap), rect))
+            && !allocator.keep_out.iter().any(|zone| overlaps(zone, rect))
+    }
+
+    /// Checked from the definitions instead of with the allocator's own helpers where possible
+    fn assert_consistent(allocator: &ItemAllocator) {
+        for (i, a) in allocator.used.iter().enumerate() {
+            assert!(
+                a.x + a.width <= allocator.width && a.y + a.height <= allocator.height,
+                "item {a:?} is off the plane"
+            );
+            for b in &allocator.used[i + 1..] {
+                assert!(
+                    !overlaps(&expand(a, allocator.gap), b),
+                    "items {a:?} and {b:?} are closer than the gap"
+                );
+            }
+        }
+
+        for free in &allocator.free_list {
+            assert!(free.width > 0 && free.height > 0, "empty {free:?}");
+            assert!(is_free(allocator, free), "{free:?} isn't free");
+
+            // Maximal: it can't grow by a millimetre in any direction
+            let grown = [
+                free.x.checked_sub(1).map(|x| Rectangle {
+                    x,
+                    width: free.width + 1,
+                    ..*free
+                }),
+                free.y.checked_sub(1).map(|y| Rectangle {
+                    y,
+                    height: free.height + 1,
+                    ..*free
+                }),
+                Some(Rectangle {
+                    width: free.width + 1,
+                    ..*free
+                }),
+                Some(Rectangle {
+                    height: free.height + 1,
+                    ..*free
+                }),
+            ];
+            for grown in grown.iter().flatten() {
+                assert!(!is_free(allocator, grown), "{free:?} isn't maximal");
+            }
+        }
+    }
+
+    #[test]
+    fn random_allocations_keep_the_free_space_consistent() {
+        let heuristics = [
+            PlacementHeuristic::BestShortSideFit,
+            PlacementHeuristic::BestAreaFit,
+            PlacementHeuristic::BottomLeft,
+        ];
+
+        for seed in 0..10 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let mut allocator = ItemAllocator::with_items(WIDTH, HEIGHT, &config(), Vec::new());
+            let initial = allocator.free_list.clone();
+            allocator.set_heuristic(heuristics[seed as usize % heuristics.len()]);
+
+            for _ in 0..300 {
+                if allocator.used.is_empty() || rng.random_bool(0.6) {
+                    let (width, height) = (rng.random_range(1..80), rng.random_range(1..80));
+                    if let Some(rect) = allocator.allocate(width, height) {
+                        assert_eq!((rect.width, rect.height), (width, height));
+                    }
+                } else {
+                    let index = rng.random_range(0..allocator.used.len());
+                    allocator.deallocate(allocator.used[index]).unwrap();
+                }
+                assert_consistent(&allocator);
+            }
+
+            while let Some(&rect) = allocator.used.last() {
+                allocator.deallocate(rect).unwrap();
+            }
+            assert_consistent(&allocator);
+            assert_eq!(sorted(&allocator.free_list), sorted(&initial));
+        }
+    }
+
+    #[test]
+    fn freeing_everything_frees_the_whole_plane() {
+        let config = AllocatorConfig {
+            gap_mm: 5,
+            access_point_clearance_mm: 0,
+            keep_out: Vec::new(),
+        };
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut allocator = ItemAllocator::with_items(WIDTH, HEIGHT, &config, Vec::new());
+
+        while allocator
+            .allocate(rng.random_range(1..60), rng.random_range(1..60))
+            .is_some()
+        {}
+        assert!(allocator.used.len() > 1);
+        assert_consistent(&allocator);
+
+        while !allocator.used.is_empty() {
+            let index = rng.random_range(0..allocator.used.len());
+            allocator.deallocate(allocator.used[index]).unwrap();
+        }
+        assert_eq!(
+            allocator.free_list,
+            [Rectangle {
+                x: 0,
+                y: 0,
+                width: WIDTH,
+                height: HEIGHT,
+            }]
+        );
+    }
+
+    fn sorted(rects: &[Rectangle]) -> Vec<(usize, usize, usize, usize)> {
+        let mut rects = rects
+            .iter()
+            .map(|rect| (rect.x, rect.y, rect.width, rect.height))
+            .collect::<Vec<_>>();
+        rects.sort();
+        rects
     }
 }
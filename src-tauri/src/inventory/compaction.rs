@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    backend::ActuatorBackend,
+    data_file,
+    inventory::{
+        EventKind, InventoryError, InventoryImpl, Item, PlacementHeuristic, Rectangle,
+        db::sqlite::StoredItem,
+    },
+};
+
+/// A compaction move that was started but not finished, saved as `compaction.json` in the data
+/// directory so it can be finished after a crash or an emergency stop
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PendingMove {
+    item_id: i64,
+    from: Rectangle,
+    to: Rectangle,
+    phase: MovePhase,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+enum MovePhase {
+    /// Picking the item up, it's still at `from` or on the head
+    Pick,
+    /// The item is on the head on its way to `to`
+    Carry,
+}
+
+impl PendingMove {
+    const FILE_NAME: &str = "compaction.json";
+
+    async fn load() -> Result<Option<Self>, InventoryError> {
+        Ok(data_file::load(Self::FILE_NAME).await?)
+    }
+
+    async fn save(&self) -> Result<(), InventoryError> {
+        Ok(data_file::save(Self::FILE_NAME, self).await?)
+    }
+
+    async fn clear() -> Result<(), InventoryError> {
+        Ok(data_file::clear(Self::FILE_NAME).await?)
+    }
+}
+
+impl InventoryImpl<'_> {
+    /// Move every item as close to home as it fits, one at a time, returns how many items moved
+    ///
+    /// Each item's new place is written to the database as soon as it's put down. If the
+    /// compaction gets interrupted the move in progress is finished by the next inventory
+    /// operation, the items that weren't moved yet just stay where they are.
+    pub async fn compact(&mut self) -> Result<usize, InventoryError> {
+        self.ensure_access_point_free()?;
+        self.resume_compaction().await?;
+
+        let mut items = self.db.list_all_items().await;
+        items.sort_by_key(|item| (item.rect.y, item.rect.x));
+
+        let mut moved = 0;
+        for item in items {
+            match self.compact_item(&item).await {
+                Ok(true) => moved += 1,
+                Ok(false) => {}
+                Err(err) => {
+                    // The database always has where the items really are
+                    self.allocator.reload(self.db).await;
+                    return Err(err);
+                }
+            }
+        }
+
+        println!("Compaction moved {moved} items");
+        Ok(moved)
+    }
+
+    /// Finish a compaction move that got interrupted, does nothing if there's none
+    pub(super) async fn resume_compaction(&mut self) -> Result<(), InventoryError> {
+        let Some(pending) = PendingMove::load().await? else {
+            return Ok(());
+        };
+        println!("Finishing an interrupted compaction move: {pending:?}");
+
+        match self.finish_move(&pending).await {
+            Ok(()) => {}
+            // Left over from a database that changed since, every operation would fail on it
+            Err(InventoryError::NoSuchItem(id)) => {
+                println!("Dropping the compaction move of item {id}, it's not stored anymore")
+            }
+            Err(err) => {
+                self.allocator.reload(self.db).await;
+                return Err(err);
+            }
+        }
+
+        PendingMove::clear().await
+    }
+
+    /// Move the item closer to home if there's room for it, returns whether it moved
+    async fn compact_item(&mut self, item: &StoredItem<Item>) -> Result<bool, InventoryError> {
+        let from = item.rect;
+        self.allocator.deallocate(from)?;
+
+        // Its own area is free now, so there's always a place at least as close as `from`
+        let to = self
+            .allocator
+            .find(from.width, from.height, PlacementHeuristic::BottomLeft)
+            .filter(|to| (to.y, to.x) < (from.y, from.x));
+        let Some(to) = to else {
+            self.allocator.reserve(from)?;
+            return Ok(false);
+        };
+        self.allocator.reserve(to)?;
+
+        let result = self.carry(item.id, from, to).await;
+        self.record(
+            EventKind::Move,
+            (Some(item.id), &item.name),
+            to,
+            item.amount,
+            &result,
+        )
+        .await;
+        result.map(|()| true)
+    }
+
+    /// Move the item `id` from `from` to `to`, keeping track of it in `compaction.json`
+    async fn carry(
+        &mut self,
+        id: i64,
+        from: Rectangle,
+        to: Rectangle,
+    ) -> Result<(), InventoryError> {
+        let mut pending = PendingMove {
+            item_id: id,
+            from,
+            to,
+            phase: MovePhase::Pick,
+        };
+        pending.save().await?;
+        if let Err(err) = self.pick_up(from.pick_point()).await {
+            // Nothing is on the head, the item never left `from`
+            if let InventoryError::PickMissed(_) = err {
+                PendingMove::clear().await?;
+            }
+            return Err(err);
+        }
+
+        pending.phase = MovePhase::Carry;
+        pending.save().await?;
+        self.put_down(to.pick_point()).await?;
+        self.db.move_item(id, to).await;
+        PendingMove::clear().await?;
+
+        self.backend.actuator().await.contract().await?;
+        Ok(())
+    }
+
+    /// Put the item of `pending` down where it belongs, the allocator has it where the database
+    /// says it is
+    async fn finish_move(&mut self, pending: &PendingMove) -> Result<(), InventoryError> {
+        let item = self
+            .db
+            .find_item_by_id(pending.item_id)
+            .await
+            .ok_or(InventoryError::NoSuchItem(pending.item_id))?;
+        let stored = item.rect;
+
+        let target = match pending.phase {
+            // The item might still hang on the head, put it back where it was
+            MovePhase::Pick => stored,
+            MovePhase::Carry => {
+                self.allocator.deallocate(stored)?;
+                match self.allocator.reserve(pending.to) {
+                    Ok(()) => pending.to,
+                    Err(err) => {
+                        println!("Putting the item back where it was instead: {err}");
+                        self.allocator.reserve(stored)?;
+                        stored
+                    }
+                }
+            }
+        };
+
+        let result = async {
+            self.put_down(target.pick_point()).await?;
+            self.db.move_item(pending.item_id, target).await;
+            self.backend.actuator().await.contract().await?;
+            Ok(())
+        }
+        .await;
+
+        if target != stored {
+            self.record(
+                EventKind::Move,
+                (Some(item.id), &item.name),
+                target,
+                item.amount,
+                &result,
+            )
+            .await;
+        }
+        result
+    }
+}
@@ -1,6 +1,5 @@
 use std::path::PathBuf;
 
-use directories_next::ProjectDirs;
 use rand::{Rng, distr::Alphanumeric};
 use serde::{Serialize, de::DeserializeOwned};
 use tokio::{
@@ -9,9 +8,13 @@ use tokio::{
 };
 use warp::Filter;
 
-use crate::inventory::{
-    Rectangle,
-    db::sqlite::{InventoryDBImpl, StoredItem},
+use crate::{
+    data_file, frame,
+    inventory::{
+        Rectangle,
+        db::sqlite::{InventoryDBImpl, MigrationError, StoredItem},
+        history::{EventFilter, InventoryEvent, NewEvent},
+    },
 };
 
 pub trait Item: Serialize + DeserializeOwned + Send + 'static + Clone {}
@@ -20,7 +23,7 @@ impl<T> Item for T where T: Serialize + DeserializeOwned + Send + 'static + Clon
 
 pub mod sqlite;
 
-type InventoryDB = InventoryDBImpl<super::Item>;
+type InventoryDB = InventoryDBImpl;
 
 #[derive(Debug)]
 pub struct Database {
@@ -30,11 +33,8 @@ pub struct Database {
 }
 
 impl Database {
-    pub async fn new() -> Self {
-        let proj_dir =
-            ProjectDirs::from("io.github", "pantae35872", "inert").expect("No data directory");
-
-        let image_db = proj_dir.data_dir().join("image_db");
+    pub async fn new() -> Result<Self, MigrationError> {
+        let image_db = data_file::path("image_db");
         if !image_db.exists() {
             create_dir(&image_db)
                 .await
@@ -68,9 +68,35 @@ impl Database {
             warp::serve(images_route).run(([127, 0, 0, 1], 5000)).await;
         });
 
-        Self {
-            db: InventoryDB::new().await,
+        let db = Self {
+            db: InventoryDB::new().await?,
             image_db_path: image_db,
+        };
+        db.hash_images().await;
+        Ok(db)
+    }
+
+    /// Fill in the image hashes of the items that don't have one yet
+    async fn hash_images(&self) {
+        let mut failed = 0;
+        for (id, image_id) in self.db.list_unhashed_images().await {
+            // The image id is the URL the image is served at, the file has the same name
+            let file_name = image_id.rsplit('/').next().unwrap_or_default();
+            let hash = match tokio::fs::read(self.image_db_path.join(file_name)).await {
+                Ok(image) => frame::perceptual_hash(&image).ok(),
+                Err(_) => None,
+            };
+
+            match hash {
+                Some(hash) => self.db.set_image_hash(id, hash).await,
+                None => failed += 1,
+            }
+        }
+
+        if failed > 0 {
+            println!(
+                "Failed to hash the images of {failed} items, they can't be looked up by photo"
+            );
         }
     }
 
@@ -86,6 +112,14 @@ impl Database {
             .take(64)
             .map(char::from)
             .collect();
+        let image_hash = match frame::perceptual_hash(&img) {
+            Ok(hash) => Some(hash),
+            Err(err) => {
+                println!("Failed to hash the image of {}: {err}", name.as_ref());
+                None
+            }
+        };
+
         let image_path = self.image_db_path.join(image_name.as_str());
         let mut image_file = File::create(&image_path)
             .await
@@ -104,15 +138,50 @@ impl Database {
                     display_name: name.as_ref().to_string(),
                     image_id: format!("http://127.0.0.1:5000/item_images/{image_name}"),
                 },
+                image_hash,
             )
             .await
     }
 
+    /// Record that the item `id` was moved to `rect`
+    pub async fn move_item(&self, id: i64, rect: Rectangle) {
+        let Some(mut item) = self.db.find_item_by_id(id).await else {
+            println!("Item {id} was moved but it's not in the database");
+            return;
+        };
+        item.rect = rect;
+        self.db.update_item(id, &item.data).await
+    }
+
+    pub async fn set_amount(&self, id: i64, amount: u64) {
+        self.db.set_amount(id, amount).await
+    }
+
+    /// Add an entry to the operation history, it's never changed or removed afterwards
+    pub async fn record_event(&self, event: NewEvent) {
+        self.db.record_event(event).await
+    }
+
+    pub async fn list_events(
+        &self,
+        filter: EventFilter,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<InventoryEvent> {
+        self.db.list_events(filter, offset, limit).await
+    }
+
+    /// Id and perceptual hash of the image of every item that has one
+    pub async fn list_image_hashes(&self) -> Vec<(i64, u64)> {
+        self.db.list_image_hashes().await
+    }
+
     pub async fn remove_item_by_id(&self, id: i64) {
         self.db.remove_item_by_id(id).await
     }
 
-    pub async fn find_item_by_id(&self, id: i64) -> StoredItem<super::Item> {
+    /// The item `id`, none if there's no such item
+    pub async fn find_item_by_id(&self, id: i64) -> Option<StoredItem<super::Item>> {
         self.db.find_item_by_id(id).await
     }
 
@@ -1,19 +1,123 @@
-use std::{
-    marker::PhantomData,
-    ops::{Deref, DerefMut},
-};
+use std::ops::{Deref, DerefMut};
 
-use directories_next::ProjectDirs;
-use rusqlite::Row;
+use rusqlite::{OptionalExtension, Row, params_from_iter, types::Value};
+use thiserror::Error;
 use tokio_rusqlite::{Connection, OpenFlags, params};
 
-use crate::inventory::db::Item;
+use crate::{
+    data_file,
+    inventory::{
+        self, Rectangle,
+        db::Item,
+        history::{EventFilter, InventoryEvent, NewEvent},
+    },
+};
+
+/// Schema changes, the database's `user_version` is how many of them it has gone through
+///
+/// Only ever append to this, databases out there are already past the existing ones.
+const MIGRATIONS: &[&str] = &[
+    // 1: Everything but the name and amount in a JSON blob
+    r#"
+    CREATE TABLE IF NOT EXISTS items (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL,
+        amount INTEGER,
+        data TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS events (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        timestamp INTEGER NOT NULL,
+        kind TEXT NOT NULL,
+        item_id INTEGER,
+        item_name TEXT NOT NULL,
+        rect TEXT NOT NULL,
+        amount INTEGER NOT NULL,
+        error TEXT
+    );
+    "#,
+    // 2: The item's rect, display name and image in their own columns
+    r#"
+    CREATE TABLE items_v2 (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        name TEXT NOT NULL,
+        amount INTEGER,
+        display_name TEXT NOT NULL,
+        image_id TEXT NOT NULL,
+        rect_x INTEGER NOT NULL,
+        rect_y INTEGER NOT NULL,
+        rect_width INTEGER NOT NULL,
+        rect_height INTEGER NOT NULL
+    );
+    INSERT INTO items_v2 (
+        id, name, amount, display_name, image_id, rect_x, rect_y, rect_width, rect_height
+    )
+    SELECT
+        id,
+        name,
+        amount,
+        json_extract(data, '$.display_name'),
+        json_extract(data, '$.image_id'),
+        -- Rects used to be in blocks of 0.07 motor turns, 2.8mm with the default calibration
+        CAST(ROUND(json_extract(data, '$.rect.x') * 2.8) AS INTEGER),
+        CAST(ROUND(json_extract(data, '$.rect.y') * 2.8) AS INTEGER),
+        CAST(ROUND(json_extract(data, '$.rect.width') * 2.8) AS INTEGER),
+        CAST(ROUND(json_extract(data, '$.rect.height') * 2.8) AS INTEGER)
+    FROM items;
+    -- Removed items' ids must not be handed out again, the history refers to them
+    DELETE FROM sqlite_sequence WHERE name = 'items_v2';
+    INSERT INTO sqlite_sequence (name, seq)
+        SELECT 'items_v2', seq FROM sqlite_sequence WHERE name = 'items';
+    DROP TABLE items;
+    ALTER TABLE items_v2 RENAME TO items;
+    CREATE INDEX items_name ON items (name);
+    CREATE INDEX items_display_name ON items (display_name);
+    CREATE INDEX items_rect ON items (rect_y, rect_x);
+    "#,
+    // 3: Looking up the history of an item
+    r#"
+    CREATE INDEX events_item_id ON events (item_id, id);
+    CREATE INDEX events_item_name ON events (item_name, id);
+    "#,
+    // 4: Perceptual hash of the item's photo, the app fills it in for the items that already exist
+    r#"
+    ALTER TABLE items ADD COLUMN image_hash INTEGER;
+    "#,
+];
+
+/// Columns [`StoredItem`] is read from, in order
+const ITEM_COLUMNS: &str =
+    "id, name, amount, display_name, image_id, rect_x, rect_y, rect_width, rect_height";
+
+#[derive(Debug, Error)]
+pub enum MigrationError {
+    #[error("the item database is at version {0}, newer than this build knows")]
+    TooNew(usize),
+    #[error("failed to migrate the item database: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+}
+
+/// Run every migration in [`MIGRATIONS`] the database hasn't gone through yet
+fn migrate(conn: &mut rusqlite::Connection) -> Result<(), MigrationError> {
+    let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        return Err(MigrationError::TooNew(version));
+    }
+
+    for (migration, to) in MIGRATIONS.iter().zip(1..).skip(version) {
+        println!("Migrating the item database to version {to}");
+        // Either the whole migration goes through or the database stays as it was
+        let transaction = conn.transaction()?;
+        transaction.execute_batch(migration)?;
+        transaction.pragma_update(None, "user_version", to)?;
+        transaction.commit()?;
+    }
+    Ok(())
+}
 
 #[derive(Debug)]
-pub struct InventoryDBImpl<I: Item> {
+pub struct InventoryDBImpl {
     db: Connection,
-
-    item: PhantomData<I>,
 }
 
 #[derive(Debug, Clone)]
@@ -44,7 +148,7 @@ impl<I: Item> StoredItem<I> {
     }
 }
 
-impl<I: Item> TryFrom<&Row<'_>> for StoredItem<I> {
+impl TryFrom<&Row<'_>> for StoredItem<inventory::Item> {
     type Error = rusqlite::Error;
 
     fn try_from(value: &Row<'_>) -> Result<Self, rusqlite::Error> {
@@ -52,59 +156,62 @@ impl<I: Item> TryFrom<&Row<'_>> for StoredItem<I> {
             id: value.get(0)?,
             name: value.get(1)?,
             amount: value.get(2)?,
-            data: serde_json::from_str(&value.get::<_, String>(3)?).map_err(|e| {
-                rusqlite::Error::FromSqlConversionFailure(
-                    2,
-                    rusqlite::types::Type::Text,
-                    Box::new(e),
-                )
-            })?,
+            data: inventory::Item {
+                display_name: value.get(3)?,
+                image_id: value.get(4)?,
+                rect: Rectangle {
+                    x: value.get(5)?,
+                    y: value.get(6)?,
+                    width: value.get(7)?,
+                    height: value.get(8)?,
+                },
+            },
         })
     }
 }
 
-impl<I: Item> InventoryDBImpl<I> {
-    pub async fn new() -> Self {
-        let proj_dir =
-            ProjectDirs::from("io.github", "pantae35872", "inert").expect("No data directory");
-
+impl InventoryDBImpl {
+    pub async fn new() -> Result<Self, MigrationError> {
         let db = Connection::open_with_flags(
-            proj_dir.data_dir().join("item_db.sqlite"),
+            data_file::path("item_db.sqlite"),
             OpenFlags::SQLITE_OPEN_CREATE | OpenFlags::SQLITE_OPEN_READ_WRITE,
         )
         .await
         .expect("Database open failed");
-        db.call(|conn| {
-            Ok(conn.execute(
-                r#"
-                CREATE TABLE IF NOT EXISTS items (
-                    id INTEGER PRIMARY KEY AUTOINCREMENT,
-                    name TEXT NOT NULL,
-                    amount INTEGER,
-                    data TEXT NOT NULL
-                )
-                "#,
-                params![],
-            )?)
-        })
-        .await
-        .expect("Failed to create items table");
+        db.call(|conn| Ok(migrate(conn)))
+            .await
+            .expect("Item database connection closed")?;
 
-        Self {
-            db,
-            item: PhantomData,
-        }
+        Ok(Self { db })
     }
 
-    pub async fn add_item(&self, name: impl AsRef<str>, amount: usize, item: &I) -> i64 {
+    pub async fn add_item(
+        &self,
+        name: impl AsRef<str>,
+        amount: usize,
+        item: &inventory::Item,
+        image_hash: Option<u64>,
+    ) -> i64 {
         let name = name.as_ref().to_string();
-        let json_data = serde_json::to_string(item).expect("Serialization failed");
+        let item = item.clone();
+        // SQLite integers are signed, the bits are what matters
+        let image_hash = image_hash.map(|hash| hash as i64);
 
         self.db
             .call(move |conn| {
                 conn.execute(
-                    "INSERT OR REPLACE INTO items (name, amount, data) VALUES (?1, ?2, ?3)",
-                    params![name, amount, json_data],
+                    "INSERT OR REPLACE INTO items (name, amount, display_name, image_id, rect_x, rect_y, rect_width, rect_height, image_hash) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    params![
+                        name,
+                        amount,
+                        item.display_name,
+                        item.image_id,
+                        item.rect.x,
+                        item.rect.y,
+                        item.rect.width,
+                        item.rect.height,
+                        image_hash
+                    ],
                 )?;
                 let id = conn.last_insert_rowid();
                 Ok(id)
@@ -113,6 +220,170 @@ impl<I: Item> InventoryDBImpl<I> {
             .expect("Failed to save item data")
     }
 
+    pub async fn update_item(&self, id: i64, item: &inventory::Item) {
+        let item = item.clone();
+
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE items SET display_name = ?1, image_id = ?2, rect_x = ?3, rect_y = ?4, rect_width = ?5, rect_height = ?6 WHERE id = ?7",
+                    params![
+                        item.display_name,
+                        item.image_id,
+                        item.rect.x,
+                        item.rect.y,
+                        item.rect.width,
+                        item.rect.height,
+                        id
+                    ],
+                )?;
+                Ok(())
+            })
+            .await
+            .expect("Failed to update item data")
+    }
+
+    pub async fn set_image_hash(&self, id: i64, image_hash: u64) {
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE items SET image_hash = ?1 WHERE id = ?2",
+                    params![image_hash as i64, id],
+                )?;
+                Ok(())
+            })
+            .await
+            .expect("Failed to update item image hash")
+    }
+
+    /// Id and image id of every item that has no image hash yet
+    pub async fn list_unhashed_images(&self) -> Vec<(i64, String)> {
+        self.db
+            .call(|conn| {
+                let mut stmt = conn
+                    .prepare("SELECT id, image_id FROM items WHERE image_hash IS NULL")
+                    .expect("Prepare failed");
+
+                let rows = stmt
+                    .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .expect("Query failed");
+
+                rows.map(|v| v.map_err(tokio_rusqlite::Error::Rusqlite))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .await
+            .expect("Failed to list unhashed images")
+    }
+
+    /// Id and image hash of every item that has one
+    pub async fn list_image_hashes(&self) -> Vec<(i64, u64)> {
+        self.db
+            .call(|conn| {
+                let mut stmt = conn
+                    .prepare("SELECT id, image_hash FROM items WHERE image_hash IS NOT NULL")
+                    .expect("Prepare failed");
+
+                let rows = stmt
+                    .query_map([], |row| Ok((row.get(0)?, row.get::<_, i64>(1)? as u64)))
+                    .expect("Query failed");
+
+                rows.map(|v| v.map_err(tokio_rusqlite::Error::Rusqlite))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .await
+            .expect("Failed to list image hashes")
+    }
+
+    pub async fn set_amount(&self, id: i64, amount: u64) {
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "UPDATE items SET amount = ?1 WHERE id = ?2",
+                    params![amount, id],
+                )?;
+                Ok(())
+            })
+            .await
+            .expect("Failed to update item amount")
+    }
+
+    pub async fn record_event(&self, event: NewEvent) {
+        let rect = serde_json::to_string(&event.rect).expect("Serialization failed");
+
+        self.db
+            .call(move |conn| {
+                conn.execute(
+                    "INSERT INTO events (timestamp, kind, item_id, item_name, rect, amount, error) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        event.timestamp_ms,
+                        event.kind.as_str(),
+                        event.item_id,
+                        event.item_name,
+                        rect,
+                        event.amount,
+                        event.error
+                    ],
+                )?;
+                Ok(())
+            })
+            .await
+            .expect("Failed to record event")
+    }
+
+    /// Events matching `filter`, newest first
+    pub async fn list_events(
+        &self,
+        filter: EventFilter,
+        offset: usize,
+        limit: usize,
+    ) -> Vec<InventoryEvent> {
+        self.db
+            .call(move |conn| {
+                let mut conditions = Vec::new();
+                let mut values = Vec::new();
+                if let Some(item_id) = filter.item_id {
+                    conditions.push("item_id = ?");
+                    values.push(Value::Integer(item_id));
+                }
+                if let Some(item_name) = filter.item_name {
+                    conditions.push("item_name = ?");
+                    values.push(Value::Text(item_name));
+                }
+                if let Some(kind) = filter.kind {
+                    conditions.push("kind = ?");
+                    values.push(Value::Text(kind.as_str().to_string()));
+                }
+                match filter.failed {
+                    Some(true) => conditions.push("error IS NOT NULL"),
+                    Some(false) => conditions.push("error IS NULL"),
+                    None => {}
+                }
+                if let Some(since_ms) = filter.since_ms {
+                    conditions.push("timestamp >= ?");
+                    values.push(Value::Integer(since_ms));
+                }
+
+                let mut query = "SELECT id, timestamp, kind, item_id, item_name, rect, amount, error FROM events".to_string();
+                if !conditions.is_empty() {
+                    query += " WHERE ";
+                    query += &conditions.join(" AND ");
+                }
+                query += " ORDER BY id DESC LIMIT ? OFFSET ?";
+                values.push(Value::Integer(limit as i64));
+                values.push(Value::Integer(offset as i64));
+
+                let mut stmt = conn.prepare(&query).expect("Prepare failed");
+                let rows = stmt
+                    .query_map(params_from_iter(values), |row| InventoryEvent::try_from(row))
+                    .expect("Query failed");
+
+                rows.map(|v| v.map_err(tokio_rusqlite::Error::Rusqlite))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .await
+            .expect("Failed to list events")
+    }
+
     pub async fn remove_item_by_id(&self, id: i64) {
         self.db
             .call(move |conn| {
@@ -123,26 +394,29 @@ impl<I: Item> InventoryDBImpl<I> {
             .expect("Failed to remove item by ID")
     }
 
-    pub async fn find_item_by_id(&self, id: i64) -> StoredItem<I> {
+    /// The item `id`, none if there's no such item
+    pub async fn find_item_by_id(&self, id: i64) -> Option<StoredItem<inventory::Item>> {
         self.db
             .call(move |conn| {
                 let mut stmt = conn
-                    .prepare("SELECT * FROM items WHERE id = ?1")
+                    .prepare(&format!("SELECT {ITEM_COLUMNS} FROM items WHERE id = ?1"))
                     .expect("Prepare failed");
 
-                Ok(stmt.query_row(params![id], |row| StoredItem::try_from(row))?)
+                Ok(stmt
+                    .query_row(params![id], |row| StoredItem::try_from(row))
+                    .optional()?)
             })
             .await
             .expect("Find failed")
     }
 
-    pub async fn find_item_by_name(&self, name: &str) -> Vec<StoredItem<I>> {
+    pub async fn find_item_by_name(&self, name: &str) -> Vec<StoredItem<inventory::Item>> {
         let name = name.to_string();
 
         self.db
             .call(move |conn| {
                 let mut stmt = conn
-                    .prepare("SELECT * FROM items WHERE name = ?1")
+                    .prepare(&format!("SELECT {ITEM_COLUMNS} FROM items WHERE name = ?1"))
                     .expect("Prepare failed");
 
                 let rows = stmt
@@ -156,11 +430,11 @@ impl<I: Item> InventoryDBImpl<I> {
             .expect("Find failed")
     }
 
-    pub async fn list_all_items(&self) -> Vec<StoredItem<I>> {
+    pub async fn list_all_items(&self) -> Vec<StoredItem<inventory::Item>> {
         self.db
             .call(|conn| {
                 let mut stmt = conn
-                    .prepare("SELECT * FROM items")
+                    .prepare(&format!("SELECT {ITEM_COLUMNS} FROM items"))
                     .expect("Failed to prepare SELECT statement");
 
                 let rows = stmt
@@ -174,3 +448,133 @@ impl<I: Item> InventoryDBImpl<I> {
             .expect("Failed to fetch items")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rusqlite::Connection;
+
+    use super::*;
+
+    fn item_json(x: usize, y: usize, image_id: &str, display_name: &str) -> String {
+        format!(
+            r#"{{"rect":{{"x":{x},"y":{y},"width":22,"height":22}},"image_id":"{image_id}","display_name":"{display_name}"}}"#
+        )
+    }
+
+    #[test]
+    fn migrates_items_stored_as_json() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        // The table as it was before there were migrations
+        conn.execute_batch(
+            "CREATE TABLE items (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL,
+                amount INTEGER,
+                data TEXT NOT NULL
+            )",
+        )
+        .unwrap();
+        for (name, amount, data) in [
+            ("screws", 40, item_json(0, 0, "screws.jpeg", "Screws")),
+            ("nuts", 12, item_json(25, 10, "nuts.jpeg", "Nuts")),
+            ("washers", 3, item_json(50, 0, "washers.jpeg", "Washers")),
+        ] {
+            conn.execute(
+                "INSERT INTO items (name, amount, data) VALUES (?1, ?2, ?3)",
+                rusqlite::params![name, amount, data],
+            )
+            .unwrap();
+        }
+        conn.execute("DELETE FROM items WHERE name = 'washers'", [])
+            .unwrap();
+
+        migrate(&mut conn).unwrap();
+
+        let version: usize = conn
+            .pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap();
+        assert_eq!(version, MIGRATIONS.len());
+
+        let items = conn
+            .prepare(&format!("SELECT {ITEM_COLUMNS} FROM items ORDER BY id"))
+            .unwrap()
+            .query_map([], |row| StoredItem::try_from(row))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let items = items
+            .iter()
+            .map(|item| {
+                (
+                    item.id,
+                    item.name.as_str(),
+                    item.amount,
+                    item.display_name.as_str(),
+                    item.image_id.as_str(),
+                    item.rect,
+                )
+            })
+            .collect::<Vec<_>>();
+        // Blocks of 2.8mm
+        assert_eq!(
+            items,
+            [
+                (
+                    1,
+                    "screws",
+                    40,
+                    "Screws",
+                    "screws.jpeg",
+                    Rectangle {
+                        x: 0,
+                        y: 0,
+                        width: 62,
+                        height: 62,
+                    }
+                ),
+                (
+                    2,
+                    "nuts",
+                    12,
+                    "Nuts",
+                    "nuts.jpeg",
+                    Rectangle {
+                        x: 70,
+                        y: 28,
+                        width: 62,
+                        height: 62,
+                    }
+                ),
+            ]
+        );
+
+        let unhashed: usize = conn
+            .query_row(
+                "SELECT COUNT(*) FROM items WHERE image_hash IS NULL",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(unhashed, 2);
+
+        // The removed item's id isn't handed out again
+        conn.execute(
+            "INSERT INTO items (name, amount, display_name, image_id, rect_x, rect_y, rect_width, rect_height) VALUES ('bolts', 5, 'Bolts', 'bolts.jpeg', 0, 100, 40, 40)",
+            [],
+        )
+        .unwrap();
+        assert_eq!(conn.last_insert_rowid(), 4);
+    }
+
+    #[test]
+    fn refuses_a_newer_database() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+
+        assert!(matches!(
+            migrate(&mut conn),
+            Err(MigrationError::TooNew(version)) if version == MIGRATIONS.len() + 1
+        ));
+    }
+}
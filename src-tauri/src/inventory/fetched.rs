@@ -0,0 +1,43 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    data_file,
+    inventory::{InventoryError, InventoryImpl},
+};
+
+/// The item waiting at the access point, saved as `fetched.json` in the data directory so it
+/// still has to be returned after a restart
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(super) struct FetchedItem {
+    id: i64,
+}
+
+impl FetchedItem {
+    const FILE_NAME: &str = "fetched.json";
+
+    /// The item that was left at the access point, none if it's free
+    pub(super) async fn load() -> Result<Option<i64>, InventoryError> {
+        let fetched: Option<Self> = data_file::load(Self::FILE_NAME).await?;
+        Ok(fetched.map(|fetched| fetched.id))
+    }
+
+    async fn save(&self) -> Result<(), InventoryError> {
+        Ok(data_file::save(Self::FILE_NAME, self).await?)
+    }
+
+    async fn clear() -> Result<(), InventoryError> {
+        Ok(data_file::clear(Self::FILE_NAME).await?)
+    }
+}
+
+impl InventoryImpl<'_> {
+    /// Keep track of the item at the access point, `id` is none once it's free again
+    pub(super) async fn set_fetched(&mut self, id: Option<i64>) -> Result<(), InventoryError> {
+        *self.fetched = id;
+
+        match id {
+            Some(id) => FetchedItem { id }.save().await,
+            None => FetchedItem::clear().await,
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rusqlite::{Row, types::Type};
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::inventory::{InventoryError, Rectangle};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub enum EventKind {
+    Add,
+    /// Taken out of the inventory for good, `amount` is how many were left in it
+    ///
+    /// Taking the last of an item is a `Take` of what was taken followed by a `Remove` of 0.
+    Remove,
+    Take,
+    Restock,
+    /// Moved to another slot by a compaction
+    Move,
+}
+
+impl EventKind {
+    /// Name stored in the `kind` column
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Add => "Add",
+            Self::Remove => "Remove",
+            Self::Take => "Take",
+            Self::Restock => "Restock",
+            Self::Move => "Move",
+        }
+    }
+
+    fn parse(kind: &str) -> Option<Self> {
+        [
+            Self::Add,
+            Self::Remove,
+            Self::Take,
+            Self::Restock,
+            Self::Move,
+        ]
+        .into_iter()
+        .find(|known| known.as_str() == kind)
+    }
+}
+
+/// Operation on an item as recorded in the history
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct InventoryEvent {
+    id: i64,
+    /// Milliseconds since the unix epoch
+    timestamp_ms: i64,
+    kind: EventKind,
+    /// None when adding the item failed before it got one
+    item_id: Option<i64>,
+    item_name: String,
+    /// Where the item was, or where it was moved to
+    rect: Rectangle,
+    /// How many were added, removed, taken or restocked
+    amount: u64,
+    /// Why the operation failed, none if it went through
+    error: Option<String>,
+}
+
+impl TryFrom<&Row<'_>> for InventoryEvent {
+    type Error = rusqlite::Error;
+
+    fn try_from(value: &Row<'_>) -> Result<Self, rusqlite::Error> {
+        let kind = value.get::<_, String>(2)?;
+
+        Ok(Self {
+            id: value.get(0)?,
+            timestamp_ms: value.get(1)?,
+            kind: EventKind::parse(&kind).ok_or_else(|| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    2,
+                    Type::Text,
+                    format!("unknown event kind {kind}").into(),
+                )
+            })?,
+            item_id: value.get(3)?,
+            item_name: value.get(4)?,
+            rect: serde_json::from_str(&value.get::<_, String>(5)?).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(5, Type::Text, Box::new(e))
+            })?,
+            amount: value.get(6)?,
+            error: value.get(7)?,
+        })
+    }
+}
+
+/// An event about to be added to the history
+#[derive(Debug, Clone)]
+pub struct NewEvent {
+    pub timestamp_ms: i64,
+    pub kind: EventKind,
+    pub item_id: Option<i64>,
+    pub item_name: String,
+    pub rect: Rectangle,
+    pub amount: u64,
+    pub error: Option<String>,
+}
+
+impl NewEvent {
+    /// Event happening now, `result` is the outcome of the operation
+    pub fn new<T>(
+        kind: EventKind,
+        (item_id, item_name): (Option<i64>, &str),
+        rect: Rectangle,
+        amount: u64,
+        result: &Result<T, InventoryError>,
+    ) -> Self {
+        let timestamp_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.as_millis() as i64);
+
+        Self {
+            timestamp_ms,
+            kind,
+            item_id,
+            item_name: item_name.to_string(),
+            rect,
+            amount,
+            error: result.as_ref().err().map(ToString::to_string),
+        }
+    }
+}
+
+/// Which events to list, every field that's set has to match
+#[derive(Debug, Default, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct EventFilter {
+    pub item_id: Option<i64>,
+    pub item_name: Option<String>,
+    pub kind: Option<EventKind>,
+    /// Only failed operations when set, only successful ones when cleared
+    pub failed: Option<bool>,
+    /// Only events at or after this time, in milliseconds since the unix epoch
+    pub since_ms: Option<i64>,
+}
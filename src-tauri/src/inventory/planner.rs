@@ -0,0 +1,150 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::{
+    backend::{ActuatorBackend, Backend, MotionProfile},
+    inventory::SETTLE_TIME,
+};
+
+/// Order to fetch items in, each one is brought to the access point and put back in its slot
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct RetrievalPlan {
+    /// Item ids, in the order they're fetched
+    order: Vec<i64>,
+    /// How far the head travels empty from one slot to the next, in millimetres
+    empty_travel_mm: usize,
+    /// Time the machine takes for the whole plan, not counting the user at the access point
+    estimated_seconds: f32,
+}
+
+impl RetrievalPlan {
+    /// Item ids, in the order they're fetched
+    pub fn order(&self) -> &[i64] {
+        &self.order
+    }
+
+    /// Plan fetching the items stored at `slots` to `access_point` with the head starting at
+    /// `start`
+    pub async fn new(
+        backend: &Backend,
+        start: (usize, usize),
+        access_point: (usize, usize),
+        slots: Vec<(i64, (usize, usize))>,
+    ) -> Self {
+        let order = plan_order(start, slots);
+        let actuator = backend.actuator().await.travel_time();
+
+        let mut time = Duration::ZERO;
+        let mut at = start;
+        for &(_, slot) in &order {
+            time += backend.travel_time(at, slot, MotionProfile::fast()).await;
+            // There and back again, carrying the item both ways
+            time += 2 * backend
+                .travel_time(slot, access_point, MotionProfile::gentle())
+                .await;
+            // Extended and contracted to pick up and put down, at the slot and at the access point
+            time += 8 * actuator + SETTLE_TIME;
+            at = slot;
+        }
+
+        Self {
+            empty_travel_mm: path_length(start, &order),
+            order: order.into_iter().map(|(id, _)| id).collect(),
+            estimated_seconds: time.as_secs_f32(),
+        }
+    }
+}
+
+/// Order `stops` so the head travels as little as possible between them, starting at `start`
+///
+/// The nearest neighbour path is improved with 2-opt until reversing any part of it no longer
+/// makes it shorter.
+pub fn plan_order<T>(
+    start: (usize, usize),
+    mut stops: Vec<(T, (usize, usize))>,
+) -> Vec<(T, (usize, usize))> {
+    let mut order = Vec::with_capacity(stops.len());
+    let mut at = start;
+    while let Some(next) = (0..stops.len()).min_by_key(|&i| distance(at, stops[i].1)) {
+        let stop = stops.remove(next);
+        at = stop.1;
+        order.push(stop);
+    }
+
+    let mut best = path_length(start, &order);
+    let mut improved = true;
+    while improved {
+        improved = false;
+        for i in 0..order.len() {
+            for j in i + 1..order.len() {
+                order[i..=j].reverse();
+                let length = path_length(start, &order);
+                if length < best {
+                    best = length;
+                    improved = true;
+                } else {
+                    order[i..=j].reverse();
+                }
+            }
+        }
+    }
+
+    order
+}
+
+/// How far the head travels going through `stops` from `start`
+fn path_length<T>(start: (usize, usize), stops: &[(T, (usize, usize))]) -> usize {
+    stops
+        .iter()
+        .scan(start, |at, &(_, stop)| {
+            Some(distance(std::mem::replace(at, stop), stop))
+        })
+        .sum()
+}
+
+/// How far the head travels between two points, both axes move at once so the longer one counts
+fn distance((x1, y1): (usize, usize), (x2, y2): (usize, usize)) -> usize {
+    x1.abs_diff(x2).max(y1.abs_diff(y2))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    #[test]
+    fn finds_the_shortest_path_nearest_neighbour_misses() {
+        let stops = vec![(1, (30, 60)), (2, (0, 70)), (3, (50, 90)), (4, (80, 30))];
+        // Nearest neighbour goes 1, 2, 3, 4 for 200mm
+        let order = plan_order((0, 0), stops);
+
+        assert_eq!(
+            order.iter().map(|&(id, _)| id).collect::<Vec<_>>(),
+            [2, 1, 3, 4]
+        );
+        assert_eq!(path_length((0, 0), &order), 190);
+    }
+
+    #[test]
+    fn is_never_longer_than_the_given_order() {
+        let mut rng = StdRng::seed_from_u64(0);
+
+        for _ in 0..200 {
+            let start = (rng.random_range(0..500), rng.random_range(0..400));
+            let stops = (0..rng.random_range(0..12))
+                .map(|id| (id, (rng.random_range(0..500), rng.random_range(0..400))))
+                .collect::<Vec<_>>();
+
+            let order = plan_order(start, stops.clone());
+
+            assert!(path_length(start, &order) <= path_length(start, &stops));
+            let mut ids = order.iter().map(|&(id, _)| id).collect::<Vec<_>>();
+            ids.sort();
+            assert_eq!(ids, (0..stops.len()).collect::<Vec<_>>());
+        }
+    }
+}
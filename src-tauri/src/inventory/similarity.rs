@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use ts_rs::TS;
+
+use crate::{
+    frame,
+    inventory::{DisplayItem, Inventory, InventoryError},
+};
+
+/// Photos whose hashes differ in more bits than this are of different items
+const MAX_HASH_DISTANCE: u32 = 12;
+
+/// Stored item that looks like what the camera sees
+#[derive(Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct SimilarItem {
+    item: DisplayItem,
+    /// How many bits of the perceptual hashes of the photos differ, 0 when they look the same
+    distance: u32,
+}
+
+impl Inventory {
+    /// Up to `limit` stored items whose photo looks like the JPEG `frame`, most alike first
+    pub async fn similar_items(
+        &self,
+        frame: &[u8],
+        limit: usize,
+    ) -> Result<Vec<SimilarItem>, InventoryError> {
+        let hash = frame::perceptual_hash(frame)?;
+
+        let mut distances = self
+            .db
+            .list_image_hashes()
+            .await
+            .into_iter()
+            .map(|(id, item_hash)| (id, frame::hash_distance(hash, item_hash)))
+            .filter(|&(_, distance)| distance <= MAX_HASH_DISTANCE)
+            .collect::<HashMap<_, _>>();
+        if distances.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut similar = self
+            .list_items()
+            .await
+            .into_iter()
+            .filter_map(|item| {
+                let distance = distances.remove(&item.id)?;
+                Some(SimilarItem { item, distance })
+            })
+            .collect::<Vec<_>>();
+        similar.sort_by_key(|similar| similar.distance);
+        similar.truncate(limit);
+
+        Ok(similar)
+    }
+}
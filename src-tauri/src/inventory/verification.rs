@@ -0,0 +1,55 @@
+use serde::Deserialize;
+
+use crate::{
+    backend::MagnetBackend,
+    frame,
+    inventory::{InventoryError, InventoryImpl},
+};
+
+/// How often a pick is tried before it fails
+const PICK_ATTEMPTS: usize = 2;
+
+/// Whether picks are checked with the camera, read from `verification.toml`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct VerificationConfig {
+    /// Compare what the camera sees before and after every pick
+    pub enabled: bool,
+    /// How much the frame has to change for a pick to count, as the mean brightness difference
+    /// between 0 and 1
+    pub min_difference: f32,
+}
+
+impl Default for VerificationConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            min_difference: 0.1,
+        }
+    }
+}
+
+impl InventoryImpl<'_> {
+    /// Lift whatever is under the head and check that something came up with it, leaves the
+    /// actuator contracted and the magnet on
+    ///
+    /// When the camera sees no difference the pick is tried again, after the last miss the magnet
+    /// is turned off and nothing is on the head.
+    pub(super) async fn lift_verified(&mut self, at: (usize, usize)) -> Result<(), InventoryError> {
+        for attempt in 1..=PICK_ATTEMPTS {
+            let before = self.capture().await?;
+            self.lift().await?;
+            let after = self.capture().await?;
+
+            let difference = frame::difference(&before, &after)?;
+            if difference >= self.verification.min_difference {
+                return Ok(());
+            }
+
+            println!("Pick {attempt} at {at:?} missed, the frame only changed by {difference}");
+            self.backend.magnet().await.set(false).await?;
+        }
+
+        Err(InventoryError::PickMissed(at))
+    }
+}
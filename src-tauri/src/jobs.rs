@@ -0,0 +1,279 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicU64, Ordering},
+};
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, Manager};
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+use ts_rs::TS;
+
+use crate::{
+    Direction,
+    backend::{
+        ActuatorBackend, Backend, BackendError, MagnetBackend, MotionProfile,
+        calibration::Calibration,
+    },
+    inventory::{Inventory, InventoryError, QuantityChange, Rectangle},
+    plane::{MotionMode, Plane, PlaneError},
+};
+
+pub type JobId = u64;
+
+/// Anything that moves the machine, jobs run one at a time in the order they were submitted
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub enum Job {
+    Homing,
+    /// Measure the plane again and rebuild the free space from the new size
+    MeasurePlane,
+    /// Move the head to `x`, `y` in millimetres from the home position
+    MoveTo {
+        x: usize,
+        y: usize,
+    },
+    /// Move the head by `amount` millimetres
+    MoveBy {
+        direction: Direction,
+        amount: usize,
+    },
+    /// Move the head to where the user puts down new items
+    MoveToAccessPoint,
+    /// Pick up the item at the access point and store it in `rect`
+    AddItem {
+        name: String,
+        rect: Rectangle,
+        amount: usize,
+    },
+    /// Bring the item back to the access point
+    RemoveItem {
+        id: i64,
+    },
+    /// Bring the item to the access point until it's returned
+    FetchItem {
+        id: i64,
+    },
+    /// Update the amount of the fetched item and put it back, or remove it
+    ReturnItem {
+        id: i64,
+        change: QuantityChange,
+        remove: bool,
+    },
+    /// Move the stored items closer to home to merge the free space
+    Compact,
+    ExtendActuator,
+    ContractActuator,
+    SetMagnet {
+        on: bool,
+    },
+    /// Save the calibration and use it from the next move on
+    SetCalibration {
+        calibration: Calibration,
+    },
+    SetMotionMode {
+        mode: MotionMode,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed { error: String },
+}
+
+/// Payload of the `job-status` event, sent every time a job changes state
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct JobStatus {
+    id: JobId,
+    job: Job,
+    state: JobState,
+}
+
+#[derive(Debug, Error)]
+pub enum JobError {
+    #[error(transparent)]
+    Backend(#[from] BackendError),
+    #[error(transparent)]
+    Inventory(#[from] InventoryError),
+    #[error(transparent)]
+    Plane(#[from] PlaneError),
+    #[error("the job queue has stopped")]
+    QueueClosed,
+}
+
+struct QueuedJob {
+    id: JobId,
+    job: Job,
+    done: oneshot::Sender<Result<(), JobError>>,
+}
+
+/// Handle to the task that runs every job on the machine
+pub struct JobQueue {
+    app: AppHandle,
+    sender: mpsc::UnboundedSender<QueuedJob>,
+    next_id: AtomicU64,
+}
+
+impl JobQueue {
+    /// Spawn the job runner, `Backend`, `Plane` and `Inventory` have to be managed by `app`
+    pub fn new(app: AppHandle) -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tauri::async_runtime::spawn(run_queue(app.clone(), receiver));
+
+        Self {
+            app,
+            sender,
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Queue `job` and wait for it to finish
+    pub async fn run(&self, job: Job) -> Result<(), JobError> {
+        let (_, done) = self.submit(job)?;
+        done.await.map_err(|_| JobError::QueueClosed)?
+    }
+
+    /// Queue `job` without waiting for it, its progress is reported through `job-status` events
+    pub fn submit(
+        &self,
+        job: Job,
+    ) -> Result<(JobId, oneshot::Receiver<Result<(), JobError>>), JobError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (done, receiver) = oneshot::channel();
+        let job = QueuedJob { id, job, done };
+
+        emit_status(&self.app, &job, JobState::Queued);
+        self.sender.send(job).map_err(|_| JobError::QueueClosed)?;
+
+        Ok((id, receiver))
+    }
+}
+
+async fn run_queue(app: AppHandle, mut receiver: mpsc::UnboundedReceiver<QueuedJob>) {
+    while let Some(job) = receiver.recv().await {
+        emit_status(&app, &job, JobState::Running);
+        let result = run_job(&app, &job.job).await;
+
+        let backend = app.state::<Arc<Backend>>();
+        if backend.emergency_stop().is_triggered()
+            && let Err(err) = backend.secure().await
+        {
+            println!("Failed to secure the machine after an emergency stop: {err}");
+        }
+
+        match &result {
+            Ok(()) => emit_status(&app, &job, JobState::Done),
+            Err(err) => emit_status(
+                &app,
+                &job,
+                JobState::Failed {
+                    error: err.to_string(),
+                },
+            ),
+        }
+
+        // Whoever queued the job might not be waiting for it
+        let _ = job.done.send(result);
+    }
+}
+
+fn emit_status(app: &AppHandle, job: &QueuedJob, state: JobState) {
+    let status = JobStatus {
+        id: job.id,
+        job: job.job.clone(),
+        state,
+    };
+    if let Err(err) = app.emit("job-status", status) {
+        println!("Failed to report the status of job {}: {err}", job.id);
+    }
+}
+
+async fn run_job(app: &AppHandle, job: &Job) -> Result<(), JobError> {
+    let backend = app.state::<Arc<Backend>>();
+    let plane = app.state::<Plane>();
+    let inventory = app.state::<Inventory>();
+
+    match job.clone() {
+        Job::Homing => {
+            plane.get(Arc::clone(&backend)).await.homeing().await?;
+        }
+        Job::MeasurePlane => {
+            let mut plane = plane.get(Arc::clone(&backend)).await;
+            let measured = plane.measure().await;
+            // Saving the new size can fail after the plane already uses it
+            inventory.resize(&plane).await;
+            measured?;
+        }
+        Job::MoveTo { x, y } => {
+            let mut plane = plane.get(Arc::clone(&backend)).await;
+
+            println!("Moving to posisiton: {x}mm, {y}mm");
+            plane.move_to(x, y, MotionProfile::fast()).await?;
+            let (x, y) = plane.current_x_y();
+            println!("Current posisiton: {x}mm, {y}mm");
+        }
+        Job::MoveBy { direction, amount } => {
+            let amount = amount as isize;
+            let mut plane = plane.get(Arc::clone(&backend)).await;
+
+            match direction {
+                Direction::North => plane.move_with(0, amount, MotionProfile::fast()).await?,
+                Direction::South => plane.move_with(0, -amount, MotionProfile::fast()).await?,
+                Direction::East => plane.move_with(amount, 0, MotionProfile::fast()).await?,
+                Direction::West => plane.move_with(-amount, 0, MotionProfile::fast()).await?,
+            }
+            let (x, y) = plane.current_x_y();
+            println!("Current posisiton: {x}mm, {y}mm");
+        }
+        Job::MoveToAccessPoint => {
+            let plane = plane.get(Arc::clone(&backend)).await;
+            let mut inventory = inventory.get(Arc::clone(&backend), plane).await;
+
+            inventory.move_to_access_point().await?;
+        }
+        Job::AddItem { name, rect, amount } => {
+            let plane = plane.get(Arc::clone(&backend)).await;
+            let mut inventory = inventory.get(Arc::clone(&backend), plane).await;
+
+            inventory.add_item(name, rect, amount).await?;
+        }
+        Job::RemoveItem { id } => {
+            let plane = plane.get(Arc::clone(&backend)).await;
+            let mut inventory = inventory.get(Arc::clone(&backend), plane).await;
+
+            inventory.remove_item(id).await?;
+        }
+        Job::FetchItem { id } => {
+            let plane = plane.get(Arc::clone(&backend)).await;
+            let mut inventory = inventory.get(Arc::clone(&backend), plane).await;
+
+            inventory.fetch_item(id).await?;
+        }
+        Job::ReturnItem { id, change, remove } => {
+            let plane = plane.get(Arc::clone(&backend)).await;
+            let mut inventory = inventory.get(Arc::clone(&backend), plane).await;
+
+            inventory.return_item(id, change, remove).await?;
+        }
+        Job::Compact => {
+            let plane = plane.get(Arc::clone(&backend)).await;
+            let mut inventory = inventory.get(Arc::clone(&backend), plane).await;
+
+            inventory.compact().await?;
+        }
+        Job::ExtendActuator => backend.actuator().await.extend().await?,
+        Job::ContractActuator => backend.actuator().await.contract().await?,
+        Job::SetMagnet { on } => backend.magnet().await.set(on).await?,
+        Job::SetCalibration { calibration } => backend.set_calibration(calibration).await?,
+        Job::SetMotionMode { mode } => {
+            plane.get(Arc::clone(&backend)).await.set_motion_mode(mode);
+        }
+    }
+
+    Ok(())
+}
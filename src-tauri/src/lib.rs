@@ -1,32 +1,65 @@
-#![feature(iter_next_chunk)]
 #![feature(iter_collect_into)]
 
-use std::{
-    env,
-    path::{Path, PathBuf},
-    process::Stdio,
-    str::FromStr,
-    sync::Arc,
-};
+use std::{collections::HashMap, env, sync::Arc};
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 use tauri::{AppHandle, Emitter, Manager};
-use tokio::{
-    fs::File,
-    io::{AsyncReadExt, AsyncWriteExt},
-    process::Command,
-};
+use thiserror::Error;
+use tokio::sync::broadcast::error::RecvError;
 use ts_rs::TS;
 
 use crate::{
-    backend::{ActuatorBackend, Backend, CameraBackend, CameraFrame, MagnetBackend},
-    inventory::{DisplayItem, Inventory, Rectangle},
-    plane::Plane,
+    backend::{
+        ActuatorBackend, Backend, BackendError, CameraBackend, CameraFrame, MagnetBackend,
+        calibration::Calibration,
+    },
+    inventory::{
+        DisplayItem, EventFilter, Inventory, InventoryError, InventoryEvent, ItemSize,
+        PlacementHeuristic, QuantityChange, Rectangle, RetrievalPlan, SimilarItem,
+    },
+    jobs::{Job, JobError, JobQueue},
+    plane::{MotionMode, Plane},
+    recognition::Recognition,
+    requests::{
+        ItemRequest, RequestError, RequestId, RequestOrder, RequestQueue, RequestedItem,
+        process_requests,
+    },
 };
 
+/// What `benches/` measures, not part of the app
+#[cfg(feature = "bench")]
+pub mod bench {
+    pub use crate::inventory::allocator::{AllocatorConfig, ItemAllocator, PlacementHeuristic};
+}
+
 mod backend;
+mod config;
+mod data_file;
+mod frame;
 mod inventory;
+mod jobs;
 mod plane;
+mod recognition;
+mod requests;
+
+/// Error returned to the frontend, serialized as its message
+#[derive(Debug, Error)]
+enum CommandError {
+    #[error(transparent)]
+    Backend(#[from] BackendError),
+    #[error(transparent)]
+    Job(#[from] JobError),
+    #[error(transparent)]
+    Request(#[from] RequestError),
+    #[error(transparent)]
+    Inventory(#[from] InventoryError),
+}
+
+impl Serialize for CommandError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
 
 #[derive(Serialize, Deserialize, TS)]
 #[ts(export)]
@@ -36,75 +69,247 @@ enum PrepareAddItemStatus {
 }
 
 #[tauri::command]
-async fn remove_item(app: AppHandle, id: i64) {
-    let inventory = app.state::<Inventory>();
-    let backend = app.state::<Arc<Backend>>();
-    let plane = app.state::<Plane>();
-    let mut inventory = inventory.get(Arc::clone(&backend), &plane).await;
+async fn remove_item(app: AppHandle, id: i64) -> Result<(), CommandError> {
+    let jobs = app.state::<JobQueue>();
+    jobs.run(Job::RemoveItem { id }).await?;
+    Ok(())
+}
+
+/// Bring the item to the access point, it stays there until [`return_item`] is called
+#[tauri::command]
+async fn fetch_item(app: AppHandle, id: i64) -> Result<(), CommandError> {
+    let jobs = app.state::<JobQueue>();
+    jobs.run(Job::FetchItem { id }).await?;
+    Ok(())
+}
 
-    inventory.remove_item(id).await;
+/// Put the fetched item back with its amount updated, or remove it when `remove` is set
+#[tauri::command]
+async fn return_item(
+    app: AppHandle,
+    id: i64,
+    change: QuantityChange,
+    remove: bool,
+) -> Result<(), CommandError> {
+    let jobs = app.state::<JobQueue>();
+    jobs.run(Job::ReturnItem { id, change, remove }).await?;
+    Ok(())
 }
 
+/// Order to fetch `ids` in with the estimated time, starting with the head at the access point
 #[tauri::command]
-async fn prepare_add_item(app: AppHandle) -> PrepareAddItemStatus {
+async fn plan_retrieval(app: AppHandle, ids: Vec<i64>) -> Result<RetrievalPlan, CommandError> {
+    let backend = app.state::<Arc<Backend>>();
     let inventory = app.state::<Inventory>();
+    Ok(inventory.plan_retrieval(&backend, None, &ids).await?)
+}
+
+/// Plan fetching each item id with the amount wanted of it, then request them in that order
+///
+/// The amounts of an id that's listed more than once are added up.
+#[tauri::command]
+async fn retrieve_items(
+    app: AppHandle,
+    items: Vec<(i64, usize)>,
+) -> Result<RetrievalPlan, CommandError> {
     let backend = app.state::<Arc<Backend>>();
-    let plane = app.state::<Plane>();
-    let mut inventory = inventory.get(Arc::clone(&backend), &plane).await;
-    match inventory.prepare_add_item().await {
-        Some(rect) => PrepareAddItemStatus::Success(rect),
-        None => return PrepareAddItemStatus::NoSpaceLeft,
+    let inventory = app.state::<Inventory>();
+    let requests = app.state::<RequestQueue>();
+
+    // An item listed more than once is fetched once for all of it
+    let mut amounts = HashMap::<i64, usize>::new();
+    let mut ids = Vec::new();
+    for (id, amount) in items {
+        let total = amounts.entry(id).or_insert_with(|| {
+            ids.push(id);
+            0
+        });
+        *total = total.saturating_add(amount);
     }
+    let plan = inventory.plan_retrieval(&backend, None, &ids).await?;
+
+    requests
+        .add_all(
+            plan.order()
+                .iter()
+                .map(|&id| (RequestedItem::Id { id }, amounts[&id]))
+                .collect(),
+        )
+        .await?;
+
+    Ok(plan)
+}
+
+/// Queue a request for `amount` of `item`, it's brought to the access point when its turn comes
+#[tauri::command]
+async fn request_item(
+    app: AppHandle,
+    item: RequestedItem,
+    amount: usize,
+) -> Result<RequestId, CommandError> {
+    let requests = app.state::<RequestQueue>();
+    Ok(requests.add(item, amount).await?)
+}
+
+#[tauri::command]
+async fn list_requests(app: AppHandle) -> Vec<ItemRequest> {
+    let requests = app.state::<RequestQueue>();
+    requests.list().await
 }
 
+/// The user took the requested amount from the item at the access point
 #[tauri::command]
-async fn confirm_add_item(app: AppHandle, name: String, rect: Rectangle, amount: usize) {
+async fn collect_request(app: AppHandle, id: RequestId) -> Result<(), CommandError> {
+    let requests = app.state::<RequestQueue>();
+    requests.collect(id).await?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn cancel_request(app: AppHandle, id: RequestId) -> Result<(), CommandError> {
+    let requests = app.state::<RequestQueue>();
+    requests.cancel(id).await?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn retry_request(app: AppHandle, id: RequestId) -> Result<(), CommandError> {
+    let requests = app.state::<RequestQueue>();
+    requests.retry(id).await?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn set_request_order(app: AppHandle, order: RequestOrder) -> Result<(), CommandError> {
+    let requests = app.state::<RequestQueue>();
+    requests.set_order(order).await?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn prepare_add_item(
+    app: AppHandle,
+    size: ItemSize,
+) -> Result<PrepareAddItemStatus, CommandError> {
+    let jobs = app.state::<JobQueue>();
     let inventory = app.state::<Inventory>();
-    let backend = app.state::<Arc<Backend>>();
-    let plane = app.state::<Plane>();
-    let mut inventory = inventory.get(Arc::clone(&backend), &plane).await;
 
-    inventory.add_item(name, rect, amount).await;
+    jobs.run(Job::MoveToAccessPoint).await?;
+    match inventory.allocate(size).await {
+        Some(rect) => Ok(PrepareAddItemStatus::Success(rect)),
+        None => return Ok(PrepareAddItemStatus::NoSpaceLeft),
+    }
+}
+
+#[tauri::command]
+async fn confirm_add_item(
+    app: AppHandle,
+    name: String,
+    rect: Rectangle,
+    amount: usize,
+) -> Result<(), CommandError> {
+    let jobs = app.state::<JobQueue>();
+    jobs.run(Job::AddItem { name, rect, amount }).await?;
+    Ok(())
 }
 
 #[tauri::command]
 async fn list_items(app: AppHandle) -> Vec<DisplayItem> {
     let inventory = app.state::<Inventory>();
-    let backend = app.state::<Arc<Backend>>();
-    let plane = app.state::<Plane>();
-    let mut inventory = inventory.get(Arc::clone(&backend), &plane).await;
-
     inventory.list_items().await
 }
 
+/// Up to `limit` stored items that look like what the camera sees, most alike first
 #[tauri::command]
-async fn test_magnet(app: AppHandle, state: bool) {
+async fn find_similar_items(
+    app: AppHandle,
+    limit: usize,
+) -> Result<Vec<SimilarItem>, CommandError> {
     let backend = app.state::<Arc<Backend>>();
-    backend.magnet().await.set(state).await;
+    let inventory = app.state::<Inventory>();
+
+    let frame = backend.camera().await.capture();
+    let frame = frame.take().await?;
+    Ok(inventory.similar_items(&frame, limit).await?)
 }
 
+/// Page through the operation history, newest first
 #[tauri::command]
-async fn actuator_contract(app: AppHandle) {
-    let backend = app.state::<Arc<Backend>>();
-    backend.actuator().await.contract().await;
+async fn list_events(
+    app: AppHandle,
+    filter: EventFilter,
+    offset: usize,
+    limit: usize,
+) -> Vec<InventoryEvent> {
+    let inventory = app.state::<Inventory>();
+    inventory.list_events(filter, offset, limit).await
 }
 
 #[tauri::command]
-async fn actuator_extend(app: AppHandle) {
+async fn set_placement_heuristic(app: AppHandle, heuristic: PlacementHeuristic) {
+    let inventory = app.state::<Inventory>();
+    inventory.set_heuristic(heuristic).await;
+}
+
+#[tauri::command]
+async fn test_magnet(app: AppHandle, state: bool) -> Result<(), CommandError> {
+    let jobs = app.state::<JobQueue>();
+    jobs.run(Job::SetMagnet { on: state }).await?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn actuator_contract(app: AppHandle) -> Result<(), CommandError> {
+    let jobs = app.state::<JobQueue>();
+    jobs.run(Job::ContractActuator).await?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn actuator_extend(app: AppHandle) -> Result<(), CommandError> {
+    let jobs = app.state::<JobQueue>();
+    jobs.run(Job::ExtendActuator).await?;
+    Ok(())
+}
+
+/// Measure the plane again, only needed after the machine was changed
+#[tauri::command]
+async fn measure_plane(app: AppHandle) -> Result<(), CommandError> {
+    let jobs = app.state::<JobQueue>();
+    jobs.run(Job::MeasurePlane).await?;
+    Ok(())
+}
+
+/// Move the stored items closer to home so large items fit again
+#[tauri::command]
+async fn compact_inventory(app: AppHandle) -> Result<(), CommandError> {
+    let jobs = app.state::<JobQueue>();
+    jobs.run(Job::Compact).await?;
+    Ok(())
+}
+
+/// Stop the machine right away, the running job fails and nothing moves until it's reset
+#[tauri::command]
+async fn emergency_stop(app: AppHandle) {
     let backend = app.state::<Arc<Backend>>();
-    backend.actuator().await.extend().await;
+    backend.emergency_stop().trigger();
 }
 
+/// Allow the machine to move again after an emergency stop, the plane has to be homed afterwards
 #[tauri::command]
-async fn homing(app: AppHandle) {
+async fn reset_emergency_stop(app: AppHandle) {
     let backend = app.state::<Arc<Backend>>();
-    let plane = app.state::<Plane>();
-    let mut plane = plane.get(Arc::clone(&backend)).await;
+    backend.emergency_stop().reset();
+}
 
-    plane.homeing().await;
+#[tauri::command]
+async fn homing(app: AppHandle) -> Result<(), CommandError> {
+    let jobs = app.state::<JobQueue>();
+    jobs.run(Job::Homing).await?;
+    Ok(())
 }
 
-#[derive(Serialize, Deserialize, TS)]
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
 #[ts(export)]
 enum Direction {
     North,
@@ -113,46 +318,54 @@ enum Direction {
     West,
 }
 
+/// Move the head to `x`, `y` in millimetres from the home position
 #[tauri::command]
-async fn move_to(app: AppHandle, x: usize, y: usize) {
-    let backend = app.state::<Arc<Backend>>();
-    let plane = app.state::<Plane>();
-    let mut plane = plane.get(Arc::clone(&backend)).await;
+async fn move_to(app: AppHandle, x: usize, y: usize) -> Result<(), CommandError> {
+    let jobs = app.state::<JobQueue>();
+    jobs.run(Job::MoveTo { x, y }).await?;
+    Ok(())
+}
 
-    println!("Moving to posisiton: {x}, {y}");
-    plane.move_to(x, y).await;
-    let (x, y) = plane.current_x_y();
-    println!("Current posisiton: {x}, {y}");
+/// Move the head by `amount` millimetres
+#[tauri::command]
+async fn move_by(app: AppHandle, direction: Direction, amount: usize) -> Result<(), CommandError> {
+    let jobs = app.state::<JobQueue>();
+    jobs.run(Job::MoveBy { direction, amount }).await?;
+    Ok(())
 }
 
 #[tauri::command]
-async fn move_by(app: AppHandle, direction: Direction, amount: usize) {
-    let amount = amount as isize;
+async fn get_calibration(app: AppHandle) -> Calibration {
     let backend = app.state::<Arc<Backend>>();
-    let plane = app.state::<Plane>();
-    let mut plane = plane.get(Arc::clone(&backend)).await;
-    match direction {
-        Direction::North => plane.move_with(0, amount).await,
-        Direction::South => plane.move_with(0, -amount).await,
-        Direction::East => plane.move_with(amount, 0).await,
-        Direction::West => plane.move_with(-amount, 0).await,
-    }
-    let (x, y) = plane.current_x_y();
-    println!("Current posisiton: {x}, {y}");
+    backend.calibration().await
 }
 
 #[tauri::command]
-async fn serve_rpi_cam(app: AppHandle) -> String {
+async fn set_calibration(app: AppHandle, calibration: Calibration) -> Result<(), CommandError> {
+    let jobs = app.state::<JobQueue>();
+    jobs.run(Job::SetCalibration { calibration }).await?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn set_motion_mode(app: AppHandle, mode: MotionMode) -> Result<(), CommandError> {
+    let jobs = app.state::<JobQueue>();
+    jobs.run(Job::SetMotionMode { mode }).await?;
+    Ok(())
+}
+
+#[tauri::command]
+async fn serve_rpi_cam(app: AppHandle) -> Result<String, CommandError> {
     let backend = app.state::<Arc<Backend>>();
     let mut camera = backend.camera().await;
-    camera.start().await
+    Ok(camera.start().await?)
 }
 
 #[tauri::command]
-async fn stop_rpi_cam(app: AppHandle) {
+async fn stop_rpi_cam(app: AppHandle) -> Result<(), CommandError> {
     let backend = app.state::<Arc<Backend>>();
     let mut camera = backend.camera().await;
-    camera.stop().await
+    Ok(camera.stop().await?)
 }
 
 #[tauri::command]
@@ -165,13 +378,6 @@ async fn exit(app: AppHandle) {
     app.exit(0);
 }
 
-#[derive(serde::Serialize, Clone)]
-#[serde(rename_all = "camelCase")]
-struct DetectObjectResult {
-    name: String,
-    percentage: String,
-}
-
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     let mut args = env::args();
@@ -196,69 +402,74 @@ pub fn run() {
             use tauri::Manager;
 
             let (backend, plane, inventory) = tauri::async_runtime::block_on(async {
-                let backend = Arc::new(Backend::new());
-                backend.actuator().await.contract().await;
-                backend.magnet().await.set(false).await;
-                let plane = Plane::new(backend.clone()).await;
-                let inventory = Inventory::new(&plane.get(backend.clone()).await).await;
-                (backend, plane, inventory)
-            });
+                let backend = Arc::new(Backend::new().await?);
+                backend.actuator().await.contract().await?;
+                backend.magnet().await.set(false).await?;
+                let plane = Plane::new(backend.clone()).await?;
+                let inventory = Inventory::new(&plane.get(backend.clone()).await).await?;
+                Ok::<_, Box<dyn std::error::Error>>((backend, plane, inventory))
+            })?;
+            let mut plane_events = plane.subscribe();
             app.manage(backend);
             app.manage(plane);
             app.manage(inventory);
+            app.manage(JobQueue::new(app.handle().clone()));
+            app.manage(tauri::async_runtime::block_on(RequestQueue::new(
+                app.handle().clone(),
+            )));
+            tauri::async_runtime::spawn(process_requests(app.handle().clone()));
 
             let handle = app.handle().clone();
 
-            let rpi_recognition = match env::var("RPI_RECOGNITION_PATH") {
-                Ok(p) => PathBuf::from_str(&p).unwrap(),
-                Err(_) => Path::new("/home")
-                    .join(env::var("USER").expect("no USER environment variable"))
-                    .join("inert")
-                    .join("rpi-recognition"),
-            };
+            let plane_handle = handle.clone();
+            tauri::async_runtime::spawn(async move {
+                loop {
+                    match plane_events.recv().await {
+                        Ok(event) => {
+                            if let Err(err) = plane_handle.emit("plane-event", event) {
+                                println!("Failed to report a plane event: {err}");
+                            }
+                        }
+                        Err(RecvError::Lagged(_)) => continue,
+                        Err(RecvError::Closed) => break,
+                    }
+                }
+            });
+
+            let emergency_stop = app.state::<Arc<Backend>>().emergency_stop().clone();
+            let stop_handle = handle.clone();
+            tauri::async_runtime::spawn(async move {
+                loop {
+                    emergency_stop.triggered().await;
+                    if let Err(err) = stop_handle.emit("emergency-stop", true) {
+                        println!("Failed to report the emergency stop: {err}");
+                    }
+                    emergency_stop.released().await;
+                    if let Err(err) = stop_handle.emit("emergency-stop", false) {
+                        println!("Failed to report the emergency stop release: {err}");
+                    }
+                }
+            });
+
+            app.manage(Recognition::new(config::load(
+                "recognition.toml",
+                "INERT_RECOGNITION_CONFIG",
+            )?));
 
             // TODO: Move this somewhere else
             tauri::async_runtime::spawn(async move {
                 let backend = handle.state::<Arc<Backend>>();
+                let recognition = handle.state::<Recognition>();
                 loop {
                     tokio::time::sleep(std::time::Duration::from_secs(3)).await;
 
                     let frame = backend.camera().await.capture();
-                    if let Some(image) = frame.take().await {
-                        File::create("/tmp/object.jpeg")
-                            .await
-                            .unwrap()
-                            .write_all(image.as_slice())
-                            .await
-                            .unwrap();
-
-                        let mut child = Command::new(rpi_recognition.join("classify_object.sh"))
-                            .args(["/tmp/object.jpeg"])
-                            .stdout(Stdio::piped())
-                            .stderr(Stdio::null())
-                            .current_dir(rpi_recognition.clone())
-                            .spawn()
-                            .unwrap();
-
-                        let mut stdout = child.stdout.take().expect("no stdout");
-                        let mut buf = String::new();
-                        stdout.read_to_string(&mut buf).await.unwrap();
-                        if let Some([name, percentage]) = buf
-                            .split("\n")
-                            .next()
-                            .map(|buf| buf.split(":").map(|e| e.trim()))
-                            .and_then(|mut buf| buf.next_chunk::<2>().ok())
-                        {
-                            handle
-                                .emit(
-                                    "update-detected-object",
-                                    DetectObjectResult {
-                                        name: name.to_string(),
-                                        percentage: percentage.to_string(),
-                                    },
-                                )
-                                .unwrap();
-                        }
+                    let Ok(image) = frame.take().await else {
+                        continue;
+                    };
+                    match recognition.detect(&image).await {
+                        Ok(object) => handle.emit("update-detected-object", object).unwrap(),
+                        Err(err) => println!("Failed to classify the camera frame: {err}"),
                     }
                 }
             });
@@ -275,11 +486,31 @@ pub fn run() {
             test_magnet,
             move_by,
             move_to,
+            set_motion_mode,
+            get_calibration,
+            set_calibration,
             homing,
+            measure_plane,
+            emergency_stop,
+            reset_emergency_stop,
             list_items,
+            list_events,
+            find_similar_items,
             confirm_add_item,
             prepare_add_item,
+            set_placement_heuristic,
             remove_item,
+            fetch_item,
+            return_item,
+            compact_inventory,
+            plan_retrieval,
+            retrieve_items,
+            request_item,
+            list_requests,
+            collect_request,
+            cancel_request,
+            retry_request,
+            set_request_order,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
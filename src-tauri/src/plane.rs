@@ -1,54 +1,82 @@
-use std::sync::Arc;
+use std::{io, path::PathBuf, sync::Arc};
 
 use tokio::{
     join,
-    sync::{Mutex, MutexGuard},
+    sync::{Mutex, MutexGuard, broadcast},
 };
 
-use crate::backend::{Backend, MotorDirection, ProtectedMotorError};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use ts_rs::TS;
+
+use crate::{
+    backend::{
+        Backend, BackendError, MotionProfile, MotorDirection, ProtectedMotorError, rotate_mm_linear,
+    },
+    data_file::{self, DataFileError},
+};
 
 pub struct Plane {
     plane: Mutex<PlaneData>,
+    events: broadcast::Sender<PlaneEvent>,
 }
 
 impl Plane {
-    pub async fn new(backend: Arc<Backend>) -> Self {
+    pub async fn new(backend: Arc<Backend>) -> Result<Self, PlaneError> {
         let data = Mutex::new(PlaneData::default());
-        PlaneImpl {
+        let (events, _) = broadcast::channel(16);
+        let mut plane = PlaneImpl {
             backend,
             data: data.lock().await,
+            events: &events,
+        };
+
+        let result = match PlaneDimensions::load().await {
+            Ok(Some(dimensions)) => {
+                plane.homeing().await?;
+                plane.data.width = dimensions.width;
+                plane.data.height = dimensions.height;
+                Ok(())
+            }
+            Ok(None) => plane.measure().await,
+            Err(err) => {
+                println!("Measuring the plane again: {err}");
+                plane.measure().await
+            }
+        };
+        match result {
+            // The plane is measured, it's just measured again on the next boot
+            Err(err @ PlaneError::DimensionsIo { .. }) => {
+                println!("Failed to save the plane dimensions: {err}")
+            }
+            result => result?,
         }
-        .setup()
-        .await;
-
-        {
-            let plane = data.lock().await;
-
-            assert!(
-                plane.width > 100,
-                "Plane width should be more than 100 units"
-            );
-            assert!(
-                plane.height > 100,
-                "Plane height should be more than 100 units"
-            );
-            println!(
-                "Plane width: {}, Plane height: {};",
-                plane.width, plane.height
-            );
-        }
-
-        Self { plane: data }
+        println!(
+            "Plane width: {}mm, Plane height: {}mm;",
+            plane.data.width, plane.data.height
+        );
+        drop(plane);
+
+        Ok(Self {
+            plane: data,
+            events,
+        })
     }
 
     pub async fn get(&self, backend: Arc<Backend>) -> PlaneImpl<'_> {
         PlaneImpl {
             backend,
             data: self.plane.lock().await,
+            events: &self.events,
         }
     }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PlaneEvent> {
+        self.events.subscribe()
+    }
 }
 
+/// Position and size of the plane, everything is in millimetres
 #[derive(Debug, Default)]
 struct PlaneData {
     cur_x: usize,
@@ -56,11 +84,145 @@ struct PlaneData {
 
     width: usize,
     height: usize,
+
+    motion_mode: MotionMode,
+    confidence: PositionConfidence,
+}
+
+/// How much `cur_x` and `cur_y` can be trusted
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum PositionConfidence {
+    /// Homed, and every move since then ended where it was expected to
+    Confident,
+    /// A limit switch was hit away from the edge of the plane, so steps were lost somewhere.
+    /// The plane gets homed again before the next move.
+    Lost,
+    /// Never homed or a move got interrupted, the plane has to be homed explicitly
+    #[default]
+    Unknown,
+}
+
+/// Size of the plane as measured by [`PlaneImpl::measure`], in millimetres
+///
+/// Saved in `plane.json` next to `calibration.json` rather than in the item database, the plane
+/// is set up before the inventory opens the database and doesn't depend on it.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct PlaneDimensions {
+    pub width: usize,
+    pub height: usize,
+}
+
+/// The plane can't be smaller than this both ways, in millimetres
+const MIN_SIZE: usize = 100;
+/// How far [`PlaneImpl::measure`] drives the head at most, in millimetres
+const MAX_WIDTH: usize = 3000;
+const MAX_HEIGHT: usize = 3000;
+/// Measurements this close to each other are the same plane, the limit switches don't trigger
+/// at exactly the same place every time
+const MEASURE_TOLERANCE: usize = 3;
+
+impl PlaneDimensions {
+    const FILE_NAME: &str = "plane.json";
+
+    /// Make sure the plane could really be this size
+    fn check(&self) -> Result<(), PlaneError> {
+        if self.width > MIN_SIZE
+            && self.height > MIN_SIZE
+            && self.width <= MAX_WIDTH
+            && self.height <= MAX_HEIGHT
+        {
+            Ok(())
+        } else {
+            Err(PlaneError::InvalidDimensions(*self))
+        }
+    }
+
+    /// Whether `other` is a different plane, more than [`MEASURE_TOLERANCE`] off either way
+    fn differs_from(&self, other: &Self) -> bool {
+        self.width.abs_diff(other.width) > MEASURE_TOLERANCE
+            || self.height.abs_diff(other.height) > MEASURE_TOLERANCE
+    }
+
+    /// Load the dimensions from the data directory, none if the plane was never measured
+    pub async fn load() -> Result<Option<Self>, PlaneError> {
+        let Some(dimensions) = data_file::load::<Self>(Self::FILE_NAME).await? else {
+            return Ok(None);
+        };
+        dimensions.check()?;
+        Ok(Some(dimensions))
+    }
+
+    pub async fn save(&self) -> Result<(), PlaneError> {
+        Ok(data_file::save(Self::FILE_NAME, self).await?)
+    }
+}
+
+/// Sent to [`Plane::subscribe`] receivers
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub enum PlaneEvent {
+    /// A limit switch was hit unexpectedly while moving to `x`, `y`
+    PositionLost { x: isize, y: isize },
+    /// The plane was homed automatically after losing its position
+    Rehomed,
+    /// A new measurement doesn't match the saved one, stored items might not be where they were
+    DimensionsChanged {
+        stored: PlaneDimensions,
+        measured: PlaneDimensions,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum PlaneError {
+    #[error(transparent)]
+    Backend(#[from] BackendError),
+    #[error("the head position is unknown, the plane has to be homed first")]
+    PositionUnknown,
+    #[error("hit a limit switch while moving to {x}mm, {y}mm, the head position was lost")]
+    PositionLost { x: isize, y: isize },
+    #[error("failed to access the plane dimensions file {}: {source}", path.display())]
+    DimensionsIo { path: PathBuf, source: io::Error },
+    #[error("invalid plane dimensions file {}: {source}", path.display())]
+    DimensionsParse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error(
+        "a {}x{}mm plane isn't possible, it has to be more than {MIN_SIZE}mm and at most {MAX_WIDTH}x{MAX_HEIGHT}mm",
+        .0.width,
+        .0.height
+    )]
+    InvalidDimensions(PlaneDimensions),
+}
+
+impl From<DataFileError> for PlaneError {
+    fn from(err: DataFileError) -> Self {
+        match err {
+            DataFileError::Io { path, source } => Self::DimensionsIo { path, source },
+            DataFileError::Parse { path, source } => Self::DimensionsParse { path, source },
+        }
+    }
+}
+
+/// A limit switch this close to the edge of the plane is expected to be hit
+const EDGE_TOLERANCE: usize = 2;
+
+/// How [`PlaneImpl::move_with`] drives the two axes on a diagonal move
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub enum MotionMode {
+    /// Both motors run on their own, the head moves at 45° until one axis is done
+    Independent,
+    /// Both motors start and finish together, the head moves in a straight line
+    #[default]
+    Coordinated,
 }
 
 pub struct PlaneImpl<'a> {
     backend: Arc<Backend>,
     data: MutexGuard<'a, PlaneData>,
+    events: &'a broadcast::Sender<PlaneEvent>,
 }
 
 impl PlaneImpl<'_> {
@@ -68,21 +230,25 @@ impl PlaneImpl<'_> {
         (self.data.cur_x, self.data.cur_y)
     }
 
-    pub async fn setup(&mut self) {
-        self.homeing().await;
-
-        const MAX_WIDTH: usize = 1000;
-        const MAX_HEIGHT: usize = 1000;
+    /// Measure the plane by driving to the far limit switches, the result is saved and reused on
+    /// the next boot
+    pub async fn measure(&mut self) -> Result<(), PlaneError> {
+        self.homeing().await?;
 
         let backend = self.backend.clone();
         let width = tokio::spawn(async move {
             let mut motor_x = backend.motor_x().await;
             match motor_x
-                .rotate_block(MotorDirection::Clockwise, MAX_WIDTH)
+                .rotate_mm(
+                    MotorDirection::Clockwise,
+                    MAX_WIDTH,
+                    MotionProfile::default(),
+                )
                 .await
             {
-                Ok(_) => MAX_WIDTH,
-                Err(ProtectedMotorError::LimitHit { left_over }) => MAX_WIDTH - left_over,
+                Ok(_) => Ok(MAX_WIDTH),
+                Err(ProtectedMotorError::LimitHit { left_over }) => Ok(MAX_WIDTH - left_over),
+                Err(ProtectedMotorError::Backend(err)) => Err(err),
             }
         });
 
@@ -91,36 +257,68 @@ impl PlaneImpl<'_> {
             let mut motor_y = backend.motor_y().await;
 
             match motor_y
-                .rotate_block(MotorDirection::AntiClockwise, MAX_HEIGHT)
+                .rotate_mm(
+                    MotorDirection::AntiClockwise,
+                    MAX_HEIGHT,
+                    MotionProfile::default(),
+                )
                 .await
             {
-                Ok(_) => MAX_HEIGHT,
-                Err(ProtectedMotorError::LimitHit { left_over }) => MAX_HEIGHT - left_over,
+                Ok(_) => Ok(MAX_HEIGHT),
+                Err(ProtectedMotorError::LimitHit { left_over }) => Ok(MAX_HEIGHT - left_over),
+                Err(ProtectedMotorError::Backend(err)) => Err(err),
             }
         });
 
         let (width, height) = join!(width, height);
-        let (width, height) = (width.unwrap(), height.unwrap());
-
-        self.homeing().await;
+        let (width, height) = (
+            width.map_err(BackendError::from)??,
+            height.map_err(BackendError::from)??,
+        );
+
+        self.homeing().await?;
+
+        let measured = PlaneDimensions { width, height };
+        measured.check()?;
+        let stored = PlaneDimensions::load().await.unwrap_or_else(|err| {
+            println!("Not comparing the measured plane with the stored one: {err}");
+            None
+        });
+        if let Some(stored) = stored
+            && stored.differs_from(&measured)
+        {
+            println!(
+                "Warning: measured plane {}x{}mm differs from the stored {}x{}mm",
+                measured.width, measured.height, stored.width, stored.height
+            );
+            let _ = self
+                .events
+                .send(PlaneEvent::DimensionsChanged { stored, measured });
+        }
 
         self.data.width = width;
         self.data.height = height;
+        measured.save().await
     }
 
     /// Home the plane to 0, 0
-    pub async fn homeing(&mut self) {
+    pub async fn homeing(&mut self) -> Result<(), PlaneError> {
+        self.data.confidence = PositionConfidence::Unknown;
+
         let backend = self.backend.clone();
         let x_dir = tokio::spawn(async move {
             let mut motor_x = backend.motor_x().await;
             let mut timeout = 10;
 
-            while let Ok(()) = motor_x
-                .rotate_block(MotorDirection::AntiClockwise, 200)
-                .await
-                && timeout > 0
-            {
-                timeout -= 1;
+            loop {
+                match motor_x
+                    .rotate_mm(MotorDirection::AntiClockwise, 500, MotionProfile::default())
+                    .await
+                {
+                    Ok(()) if timeout > 0 => timeout -= 1,
+                    Ok(()) | Err(ProtectedMotorError::LimitHit { .. }) => return Ok(()),
+                    Err(ProtectedMotorError::Backend(err)) => return Err(err),
+                }
             }
         });
 
@@ -129,75 +327,141 @@ impl PlaneImpl<'_> {
             let mut motor_y = backend.motor_y().await;
             let mut timeout = 10;
 
-            while let Ok(()) = motor_y.rotate_block(MotorDirection::Clockwise, 200).await
-                && timeout > 0
-            {
-                timeout -= 1;
+            loop {
+                match motor_y
+                    .rotate_mm(MotorDirection::Clockwise, 500, MotionProfile::default())
+                    .await
+                {
+                    Ok(()) if timeout > 0 => timeout -= 1,
+                    Ok(()) | Err(ProtectedMotorError::LimitHit { .. }) => return Ok(()),
+                    Err(ProtectedMotorError::Backend(err)) => return Err(err),
+                }
             }
         });
 
         let (x_dir, y_dir) = join!(x_dir, y_dir);
-        let (_, _) = (x_dir.unwrap(), y_dir.unwrap());
+        x_dir.map_err(BackendError::from)??;
+        y_dir.map_err(BackendError::from)??;
 
         self.data.cur_x = 0;
         self.data.cur_y = 0;
+        self.data.confidence = PositionConfidence::Confident;
+        Ok(())
     }
 
-    /// Add the provide x and y with the current position,
+    /// Home the plane again if it lost its position, fails if the position is unknown
+    pub async fn ensure_position(&mut self) -> Result<(), PlaneError> {
+        match self.data.confidence {
+            PositionConfidence::Confident => Ok(()),
+            PositionConfidence::Unknown => Err(PlaneError::PositionUnknown),
+            PositionConfidence::Lost => {
+                println!("Position lost, homing the plane again");
+                self.homeing().await?;
+                let _ = self.events.send(PlaneEvent::Rehomed);
+                Ok(())
+            }
+        }
+    }
+
+    /// Add the provide x and y (in millimetres) with the current position,
     /// # Note
     /// Moves the plane relative to the current position by (ax, ay), not to an absolute position.
-    pub async fn move_with(&mut self, ax: isize, ay: isize) {
-        let backend = self.backend.clone();
-        let x_moved = tokio::spawn(async move {
-            let mut motor_x = backend.motor_x().await;
-            motor_x
-                .rotate_block(
-                    if ax.is_negative() {
-                        MotorDirection::AntiClockwise
-                    } else {
-                        MotorDirection::Clockwise
-                    },
-                    ax.unsigned_abs(),
+    /// Use [`MotionProfile::gentle`] when the head is carrying an item.
+    pub async fn move_with(
+        &mut self,
+        ax: isize,
+        ay: isize,
+        profile: MotionProfile,
+    ) -> Result<(), PlaneError> {
+        self.ensure_position().await?;
+
+        let (x_moved, y_moved) = match self.data.motion_mode {
+            MotionMode::Coordinated if ax != 0 && ay != 0 => {
+                let mut motor_x = self.backend.motor_x().await;
+                let mut motor_y = self.backend.motor_y().await;
+
+                let (x_result, y_result) = rotate_mm_linear(
+                    (&mut motor_x, x_direction(ax), ax.unsigned_abs()),
+                    (&mut motor_y, y_direction(ay), ay.unsigned_abs()),
+                    profile,
                 )
-                .await
-                .map(|_| ax)
-                .unwrap_or_else(|ProtectedMotorError::LimitHit { left_over }| {
-                    (ax.unsigned_abs() - left_over) as isize * ax.signum()
-                })
-        });
+                .await;
 
-        let backend = self.backend.clone();
-        let y_moved = tokio::spawn(async move {
-            let mut motor_y = backend.motor_y().await;
-            motor_y
-                .rotate_block(
-                    if ay.is_negative() {
-                        MotorDirection::Clockwise
-                    } else {
-                        MotorDirection::AntiClockwise
-                    },
-                    ay.unsigned_abs(),
+                (moved_by(ax, x_result), moved_by(ay, y_result))
+            }
+            _ => {
+                let backend = self.backend.clone();
+                let x_moved = tokio::spawn(async move {
+                    let mut motor_x = backend.motor_x().await;
+                    let result = motor_x
+                        .rotate_mm(x_direction(ax), ax.unsigned_abs(), profile)
+                        .await;
+                    moved_by(ax, result)
+                });
+
+                let backend = self.backend.clone();
+                let y_moved = tokio::spawn(async move {
+                    let mut motor_y = backend.motor_y().await;
+                    let result = motor_y
+                        .rotate_mm(y_direction(ay), ay.unsigned_abs(), profile)
+                        .await;
+                    moved_by(ay, result)
+                });
+
+                let (x_moved, y_moved) = join!(x_moved, y_moved);
+                (
+                    x_moved.map_err(BackendError::from)?,
+                    y_moved.map_err(BackendError::from)?,
                 )
-                .await
-                .map(|_| ay)
-                .unwrap_or_else(|ProtectedMotorError::LimitHit { left_over }| {
-                    (ay.unsigned_abs() - left_over) as isize * ay.signum()
-                })
-        });
+            }
+        };
+
+        if x_moved.is_err() || y_moved.is_err() {
+            // Whatever interrupted the move, there's no telling where the head stopped
+            self.data.confidence = PositionConfidence::Unknown;
+        }
+
+        let target = (self.data.cur_x as isize + ax, self.data.cur_y as isize + ay);
+        let unexpected_limit = matches!(x_moved, Ok((_, true)) if !on_edge(target.0, self.data.width))
+            || matches!(y_moved, Ok((_, true)) if !on_edge(target.1, self.data.height));
+
+        if let Ok((x_moved, _)) = x_moved {
+            self.data.cur_x = (self.data.cur_x as isize + x_moved).max(0) as usize;
+        }
+        if let Ok((y_moved, _)) = y_moved {
+            self.data.cur_y = (self.data.cur_y as isize + y_moved).max(0) as usize;
+        }
+
+        x_moved.and(y_moved)?;
+
+        if unexpected_limit {
+            let (x, y) = target;
+            println!("Hit a limit switch while moving to {x}mm, {y}mm");
 
-        let (x_moved, y_moved) = join!(x_moved, y_moved);
-        let (x_moved, y_moved) = (x_moved.unwrap(), y_moved.unwrap());
+            self.data.confidence = PositionConfidence::Lost;
+            let _ = self.events.send(PlaneEvent::PositionLost { x, y });
+            return Err(PlaneError::PositionLost { x, y });
+        }
+
+        Ok(())
+    }
 
-        self.data.cur_x = (self.data.cur_x as isize + x_moved).max(0) as usize;
-        self.data.cur_y = (self.data.cur_y as isize + y_moved).max(0) as usize;
+    pub fn set_motion_mode(&mut self, motion_mode: MotionMode) {
+        self.data.motion_mode = motion_mode;
     }
 
-    pub async fn move_to(&mut self, x: usize, y: usize) {
+    pub async fn move_to(
+        &mut self,
+        x: usize,
+        y: usize,
+        profile: MotionProfile,
+    ) -> Result<(), PlaneError> {
         self.move_with(
             x as isize - self.data.cur_x as isize,
             y as isize - self.data.cur_y as isize,
+            profile,
         )
-        .await;
+        .await
     }
 
     pub fn width(&self) -> usize {
@@ -210,3 +474,39 @@ impl PlaneImpl<'_> {
         self.data.height
     }
 }
+
+fn x_direction(ax: isize) -> MotorDirection {
+    if ax.is_negative() {
+        MotorDirection::AntiClockwise
+    } else {
+        MotorDirection::Clockwise
+    }
+}
+
+fn y_direction(ay: isize) -> MotorDirection {
+    if ay.is_negative() {
+        MotorDirection::Clockwise
+    } else {
+        MotorDirection::AntiClockwise
+    }
+}
+
+/// How far an axis actually moved, and whether it stopped at a limit switch
+fn moved_by(
+    amount: isize,
+    result: Result<(), ProtectedMotorError>,
+) -> Result<(isize, bool), BackendError> {
+    match result {
+        Ok(()) => Ok((amount, false)),
+        Err(ProtectedMotorError::LimitHit { left_over }) => Ok((
+            (amount.unsigned_abs() - left_over) as isize * amount.signum(),
+            true,
+        )),
+        Err(ProtectedMotorError::Backend(err)) => Err(err),
+    }
+}
+
+/// Whether `position` is at or past either end of an axis that is `size` long
+fn on_edge(position: isize, size: usize) -> bool {
+    position <= EDGE_TOLERANCE as isize || position >= size.saturating_sub(EDGE_TOLERANCE) as isize
+}
@@ -0,0 +1,211 @@
+use std::io;
+#[cfg(feature = "ort")]
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use ts_rs::TS;
+
+use crate::recognition::{
+    stub::{StubConfig, StubRecognizer},
+    worker::{WorkerConfig, WorkerRecognizer},
+};
+#[cfg(feature = "ort")]
+use crate::{
+    frame::FrameError,
+    recognition::onnx::{OnnxConfig, OnnxRecognizer},
+};
+
+#[cfg(feature = "ort")]
+mod onnx;
+mod stub;
+mod worker;
+
+/// What recognizes the objects under the camera, read from `recognition.toml`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RecognitionConfig {
+    pub recognizer: RecognizerKind,
+    /// How many of the most likely labels are asked for
+    pub top_k: usize,
+    /// The object is unknown when even the most likely label is less confident than this
+    pub min_confidence: f32,
+    pub worker: WorkerConfig,
+    #[cfg(feature = "ort")]
+    pub onnx: OnnxConfig,
+    pub stub: StubConfig,
+}
+
+impl Default for RecognitionConfig {
+    fn default() -> Self {
+        Self {
+            recognizer: RecognizerKind::default(),
+            top_k: 5,
+            min_confidence: 0.5,
+            worker: WorkerConfig::default(),
+            #[cfg(feature = "ort")]
+            onnx: OnnxConfig::default(),
+            stub: StubConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize)]
+pub enum RecognizerKind {
+    /// The model in `rpi-recognition`, run by a Python worker process
+    #[cfg_attr(not(feature = "sim"), default)]
+    Worker,
+    /// The same model exported to ONNX, run in the app, needs the `ort` feature
+    #[cfg(feature = "ort")]
+    Onnx,
+    /// Labels looked up by the hash of the image, doesn't need a model
+    #[cfg_attr(feature = "sim", default)]
+    Stub,
+}
+
+/// A label the image might show
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct Classification {
+    pub label: String,
+    /// Between 0 and 1
+    pub confidence: f32,
+}
+
+/// Payload of the `update-detected-object` event
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct DetectedObject {
+    /// The most likely label, none when the object is unknown
+    label: Option<String>,
+    /// Every label the recognizer came up with, most likely first
+    candidates: Vec<Classification>,
+}
+
+#[derive(Debug, Error)]
+pub enum RecognitionError {
+    #[error("failed to start the recognition worker: {0}")]
+    Spawn(io::Error),
+    #[error("failed to talk to the recognition worker: {0}")]
+    Io(#[from] io::Error),
+    #[error("the recognition worker exited")]
+    Exited,
+    #[error("the recognition worker didn't answer in time")]
+    Timeout,
+    #[error("invalid message from the recognition worker: {0}")]
+    Protocol(#[from] serde_json::Error),
+    #[error("the recognition worker failed: {0}")]
+    Worker(String),
+    #[cfg(feature = "ort")]
+    #[error("failed to read the image: {0}")]
+    Frame(#[from] FrameError),
+    #[cfg(feature = "ort")]
+    #[error("the ONNX model failed: {0}")]
+    Onnx(#[from] ort::Error),
+    #[cfg(feature = "ort")]
+    #[error("failed to read the labels {}: {source}", path.display())]
+    Labels { path: PathBuf, source: io::Error },
+}
+
+pub trait Recognizer {
+    /// The most likely labels for a JPEG image, most likely first
+    async fn classify(&self, jpeg: &[u8]) -> Result<Vec<Classification>, RecognitionError>;
+}
+
+/// One of the recognizers, picked by [`RecognitionConfig::recognizer`]
+enum AnyRecognizer {
+    Worker(Box<WorkerRecognizer>),
+    #[cfg(feature = "ort")]
+    Onnx(OnnxRecognizer),
+    Stub(StubRecognizer),
+}
+
+impl Recognizer for AnyRecognizer {
+    async fn classify(&self, jpeg: &[u8]) -> Result<Vec<Classification>, RecognitionError> {
+        match self {
+            Self::Worker(worker) => worker.classify(jpeg).await,
+            #[cfg(feature = "ort")]
+            Self::Onnx(onnx) => onnx.classify(jpeg).await,
+            Self::Stub(stub) => stub.classify(jpeg).await,
+        }
+    }
+}
+
+/// Recognizes the objects under the camera with the configured [`Recognizer`]
+pub struct Recognition {
+    recognizer: AnyRecognizer,
+    min_confidence: f32,
+}
+
+impl Recognition {
+    pub fn new(config: RecognitionConfig) -> Self {
+        let recognizer = match config.recognizer {
+            RecognizerKind::Worker => {
+                AnyRecognizer::Worker(Box::new(WorkerRecognizer::new(config.worker, config.top_k)))
+            }
+            #[cfg(feature = "ort")]
+            RecognizerKind::Onnx => {
+                AnyRecognizer::Onnx(OnnxRecognizer::new(config.onnx, config.top_k))
+            }
+            RecognizerKind::Stub => AnyRecognizer::Stub(StubRecognizer::new(config.stub)),
+        };
+
+        Self {
+            recognizer,
+            min_confidence: config.min_confidence,
+        }
+    }
+
+    /// What the JPEG image shows, unknown when the recognizer isn't confident enough
+    pub async fn detect(&self, jpeg: &[u8]) -> Result<DetectedObject, RecognitionError> {
+        let candidates = self.recognizer.classify(jpeg).await?;
+        let label = candidates
+            .first()
+            .filter(|best| best.confidence >= self.min_confidence)
+            .map(|best| best.label.clone());
+
+        Ok(DetectedObject { label, candidates })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    /// A black and white checkerboard
+    const FRAME: &[u8] = include_bytes!("../fixtures/checkerboard.jpeg");
+
+    fn stub(labels: &[(&[u8], &str)]) -> Recognition {
+        let labels = labels
+            .iter()
+            .map(|(jpeg, label)| (format!("{:x}", Sha256::digest(jpeg)), label.to_string()))
+            .collect();
+
+        Recognition::new(RecognitionConfig {
+            recognizer: RecognizerKind::Stub,
+            stub: StubConfig { labels },
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn detects_a_known_image() {
+        let recognition = stub(&[(FRAME, "screw")]);
+
+        let detected = recognition.detect(FRAME).await.unwrap();
+        assert_eq!(detected.label.as_deref(), Some("screw"));
+        assert_eq!(detected.candidates.len(), 1);
+        assert_eq!(detected.candidates[0].confidence, 1.0);
+    }
+
+    #[tokio::test]
+    async fn an_unknown_image_has_no_label() {
+        let recognition = stub(&[(b"not the frame", "screw")]);
+
+        let detected = recognition.detect(FRAME).await.unwrap();
+        assert_eq!(detected.label, None);
+        assert!(detected.candidates.is_empty());
+    }
+}
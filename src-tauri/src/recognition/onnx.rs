@@ -0,0 +1,163 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use jpeg_decoder::PixelFormat;
+use ort::{session::Session, value::Tensor};
+use serde::Deserialize;
+
+use crate::{
+    frame::{self, FrameError},
+    recognition::{Classification, RecognitionError, Recognizer, worker::WorkerConfig},
+};
+
+/// The `[onnx]` table of `recognition.toml`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OnnxConfig {
+    /// The model exported to ONNX, `model.onnx` next to the worker when unset
+    pub model: Option<PathBuf>,
+    /// The label of every model output one per line, `labels.txt` next to the worker when unset
+    pub labels: Option<PathBuf>,
+    /// The ONNX Runtime library, `ORT_DYLIB_PATH` or `libonnxruntime.so` when unset
+    pub runtime: Option<PathBuf>,
+    /// Width and height of the RGB images the model takes
+    pub input_size: usize,
+}
+
+impl Default for OnnxConfig {
+    fn default() -> Self {
+        Self {
+            model: None,
+            labels: None,
+            runtime: None,
+            input_size: 224,
+        }
+    }
+}
+
+struct OnnxModel {
+    session: Session,
+    labels: Vec<String>,
+}
+
+impl OnnxModel {
+    fn load(config: &OnnxConfig) -> Result<Self, RecognitionError> {
+        let dir = WorkerConfig::default().worker_dir();
+        let model = config
+            .model
+            .clone()
+            .unwrap_or_else(|| dir.join("model.onnx"));
+        let labels = config
+            .labels
+            .clone()
+            .unwrap_or_else(|| dir.join("labels.txt"));
+
+        if let Some(runtime) = &config.runtime {
+            ort::init_from(runtime.display().to_string()).commit()?;
+        }
+        let session = Session::builder()?.commit_from_file(&model)?;
+        let labels = std::fs::read_to_string(&labels)
+            .map_err(|source| RecognitionError::Labels {
+                path: labels,
+                source,
+            })?
+            .lines()
+            .map(|label| label.trim().to_string())
+            .collect();
+
+        println!("Recognition model loaded from {}", model.display());
+        Ok(Self { session, labels })
+    }
+
+    fn classify(
+        &mut self,
+        jpeg: &[u8],
+        input_size: usize,
+        top_k: usize,
+    ) -> Result<Vec<Classification>, RecognitionError> {
+        let input = Tensor::from_array(([1, 3, input_size, input_size], input(jpeg, input_size)?))?;
+        let outputs = self.session.run(ort::inputs![input])?;
+        let (_, scores) = outputs[0].try_extract_tensor::<f32>()?;
+
+        // Softmax, the same as `classify.py` does with the model's output
+        let max = scores.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let exp = scores
+            .iter()
+            .map(|score| (score - max).exp())
+            .collect::<Vec<_>>();
+        let sum = exp.iter().sum::<f32>();
+
+        let mut results = self
+            .labels
+            .iter()
+            .zip(exp)
+            .map(|(label, exp)| Classification {
+                label: label.clone(),
+                confidence: exp / sum,
+            })
+            .collect::<Vec<_>>();
+        results.sort_by(|a, b| b.confidence.total_cmp(&a.confidence));
+        results.truncate(top_k);
+        Ok(results)
+    }
+}
+
+/// The JPEG resized to `size` by `size`, as planes of red, green and blue between 0 and 1
+fn input(jpeg: &[u8], size: usize) -> Result<Vec<f32>, FrameError> {
+    let (pixels, info) = frame::decode(jpeg, size, size)?;
+    let (width, height) = (info.width as usize, info.height as usize);
+    let channel = |x: usize, y: usize, c: usize| match info.pixel_format {
+        PixelFormat::L8 => Ok(pixels[y * width + x]),
+        PixelFormat::RGB24 => Ok(pixels[(y * width + x) * 3 + c]),
+        format => Err(FrameError::PixelFormat(format)),
+    };
+
+    (0..3)
+        .flat_map(|c| (0..size).flat_map(move |y| (0..size).map(move |x| (c, x, y))))
+        .map(|(c, x, y)| {
+            let value = channel(x * width / size, y * height / size, c)?;
+            Ok(value as f32 / u8::MAX as f32)
+        })
+        .collect()
+}
+
+/// Runs the model of `rpi-recognition` exported to ONNX in the app itself, with ONNX Runtime
+///
+/// The model is loaded on the first request and loaded again after it failed to load.
+pub struct OnnxRecognizer {
+    config: OnnxConfig,
+    top_k: usize,
+    model: Arc<Mutex<Option<OnnxModel>>>,
+}
+
+impl OnnxRecognizer {
+    pub fn new(config: OnnxConfig, top_k: usize) -> Self {
+        Self {
+            config,
+            top_k,
+            model: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+impl Recognizer for OnnxRecognizer {
+    async fn classify(&self, jpeg: &[u8]) -> Result<Vec<Classification>, RecognitionError> {
+        let (config, top_k, model) = (self.config.clone(), self.top_k, self.model.clone());
+        let jpeg = jpeg.to_vec();
+
+        // Running the model takes a while, keep it off the async threads
+        tokio::task::spawn_blocking(move || {
+            let mut model = model.lock().expect("ONNX model lock poisoned");
+            if model.is_none() {
+                *model = Some(OnnxModel::load(&config)?);
+            }
+            let model = model.as_mut().expect("model was just loaded");
+
+            model.classify(&jpeg, config.input_size, top_k)
+        })
+        .await
+        .expect("ONNX recognizer panicked")
+    }
+}
@@ -0,0 +1,193 @@
+use std::{env, path::PathBuf, process::Stdio, time::Duration};
+
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    process::{Child, ChildStdin, ChildStdout, Command},
+    sync::Mutex,
+};
+
+use crate::recognition::{Classification, RecognitionError, Recognizer};
+
+/// The `[worker]` table of `recognition.toml`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkerConfig {
+    /// Directory with `recognition_worker.sh`, `RPI_RECOGNITION_PATH` or
+    /// `/home/$USER/inert/rpi-recognition` when unset
+    pub path: Option<PathBuf>,
+    /// How long the worker may take to load its model
+    pub startup_timeout_secs: u64,
+    /// How long a single image may take, the worker is restarted when it's slower
+    pub request_timeout_secs: u64,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            startup_timeout_secs: 120,
+            request_timeout_secs: 10,
+        }
+    }
+}
+
+impl WorkerConfig {
+    pub(super) fn worker_dir(&self) -> PathBuf {
+        if let Some(path) = &self.path {
+            return path.clone();
+        }
+
+        match env::var("RPI_RECOGNITION_PATH") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => PathBuf::from("/home")
+                .join(env::var("USER").expect("no USER environment variable"))
+                .join("inert")
+                .join("rpi-recognition"),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct Request<'a> {
+    id: u64,
+    /// Base64 encoded JPEG
+    image: &'a str,
+    top_k: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct Response {
+    id: Option<u64>,
+    #[serde(default)]
+    results: Vec<Classification>,
+    error: Option<String>,
+}
+
+/// Sent once by the worker when its model is loaded
+#[derive(Debug, Deserialize)]
+struct Ready {
+    ready: bool,
+}
+
+#[derive(Debug)]
+struct WorkerProcess {
+    /// Killed when dropped
+    _child: Child,
+    stdin: ChildStdin,
+    stdout: Lines<BufReader<ChildStdout>>,
+    next_id: u64,
+}
+
+impl WorkerProcess {
+    async fn spawn(config: &WorkerConfig) -> Result<Self, RecognitionError> {
+        let dir = config.worker_dir();
+        let mut child = Command::new(dir.join("recognition_worker.sh"))
+            .current_dir(&dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .kill_on_drop(true)
+            .spawn()
+            .map_err(RecognitionError::Spawn)?;
+
+        let stdin = child.stdin.take().expect("no stdin");
+        let stdout = BufReader::new(child.stdout.take().expect("no stdout")).lines();
+        let mut worker = Self {
+            _child: child,
+            stdin,
+            stdout,
+            next_id: 1,
+        };
+
+        let timeout = Duration::from_secs(config.startup_timeout_secs);
+        let line = tokio::time::timeout(timeout, worker.stdout.next_line())
+            .await
+            .map_err(|_| RecognitionError::Timeout)??
+            .ok_or(RecognitionError::Exited)?;
+        if !serde_json::from_str::<Ready>(&line)?.ready {
+            return Err(RecognitionError::Worker("not ready".to_string()));
+        }
+
+        println!("Recognition worker started from {}", dir.display());
+        Ok(worker)
+    }
+
+    async fn classify(
+        &mut self,
+        image: &str,
+        top_k: usize,
+    ) -> Result<Vec<Classification>, RecognitionError> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut request = serde_json::to_string(&Request { id, image, top_k })?;
+        request.push('\n');
+        self.stdin.write_all(request.as_bytes()).await?;
+        self.stdin.flush().await?;
+
+        loop {
+            let line = self
+                .stdout
+                .next_line()
+                .await?
+                .ok_or(RecognitionError::Exited)?;
+            let response: Response = serde_json::from_str(&line)?;
+            if response.id.is_some_and(|other| other != id) {
+                continue;
+            }
+
+            return match response.error {
+                Some(error) => Err(RecognitionError::Worker(error)),
+                None => Ok(response.results),
+            };
+        }
+    }
+}
+
+/// Runs the model in `rpi-recognition` in a long-lived worker process
+///
+/// The worker is started on the first request and started again by the request after it crashed
+/// or hung.
+pub struct WorkerRecognizer {
+    config: WorkerConfig,
+    top_k: usize,
+    process: Mutex<Option<WorkerProcess>>,
+}
+
+impl WorkerRecognizer {
+    pub fn new(config: WorkerConfig, top_k: usize) -> Self {
+        Self {
+            config,
+            top_k,
+            process: Mutex::new(None),
+        }
+    }
+}
+
+impl Recognizer for WorkerRecognizer {
+    async fn classify(&self, jpeg: &[u8]) -> Result<Vec<Classification>, RecognitionError> {
+        let mut process = self.process.lock().await;
+        if process.is_none() {
+            *process = Some(WorkerProcess::spawn(&self.config).await?);
+        }
+        let worker = process.as_mut().expect("worker was just started");
+
+        let image = STANDARD.encode(jpeg);
+        let timeout = Duration::from_secs(self.config.request_timeout_secs);
+        let result = tokio::time::timeout(timeout, worker.classify(&image, self.top_k))
+            .await
+            .unwrap_or(Err(RecognitionError::Timeout));
+
+        match result {
+            // The worker is still fine, it just couldn't make sense of the image
+            Err(RecognitionError::Worker(_)) | Ok(_) => {}
+            Err(ref err) => {
+                println!("Restarting the recognition worker: {err}");
+                *process = None;
+            }
+        }
+        result
+    }
+}
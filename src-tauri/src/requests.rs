@@ -0,0 +1,447 @@
+use std::{io, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tauri::{AppHandle, Emitter, Manager};
+use thiserror::Error;
+use tokio::sync::{Mutex, Notify};
+use ts_rs::TS;
+
+use crate::{
+    data_file::{self, DataFileError},
+    inventory::{Inventory, InventoryError, QuantityChange, Rectangle, plan_order},
+    jobs::{Job, JobError, JobQueue},
+};
+
+pub type RequestId = u64;
+
+/// Item asked for by the user
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub enum RequestedItem {
+    Id {
+        id: i64,
+    },
+    /// The stored item with this name that has the most left
+    Name {
+        name: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub enum RequestState {
+    Pending,
+    /// Being brought to the access point
+    Fetching,
+    /// Waiting at the access point for the user to take the amount out
+    Ready {
+        item_id: i64,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+/// Payload of the `requests-changed` event, together with the other requests
+#[derive(Debug, Clone, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub struct ItemRequest {
+    id: RequestId,
+    item: RequestedItem,
+    amount: usize,
+    state: RequestState,
+}
+
+/// Order pending requests are fetched in
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, TS)]
+#[ts(export)]
+pub enum RequestOrder {
+    /// The order they were made in
+    #[default]
+    InOrder,
+    /// Along the shortest path through the slots of the pending items, from where the head was
+    /// left
+    ShortestTravel,
+}
+
+#[derive(Debug, Error)]
+pub enum RequestError {
+    #[error("there's no request {0}")]
+    NotFound(RequestId),
+    #[error("request {0} is being fetched")]
+    Busy(RequestId),
+    #[error("request {0} isn't waiting at the access point")]
+    NotReady(RequestId),
+    #[error("request {0} hasn't failed")]
+    NotFailed(RequestId),
+    #[error("nothing stored matches the request")]
+    NoMatch,
+    #[error("the requested amount has to be at least 1")]
+    NoAmount,
+    #[error("only {available} left, can't request {requested}")]
+    NotEnough { available: u64, requested: usize },
+    #[error(transparent)]
+    Job(#[from] JobError),
+    #[error("failed to access the requests file {}: {source}", path.display())]
+    Io { path: PathBuf, source: io::Error },
+    #[error("invalid requests file {}: {source}", path.display())]
+    Parse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+}
+
+impl From<DataFileError> for RequestError {
+    fn from(err: DataFileError) -> Self {
+        match err {
+            DataFileError::Io { path, source } => Self::Io { path, source },
+            DataFileError::Parse { path, source } => Self::Parse { path, source },
+        }
+    }
+}
+
+/// Saved as `requests.json` in the data directory after every change
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Requests {
+    next_id: RequestId,
+    requests: Vec<ItemRequest>,
+    #[serde(skip)]
+    order: RequestOrder,
+    /// Where the head was left by the last request
+    #[serde(skip)]
+    last_slot: Option<Rectangle>,
+}
+
+impl Requests {
+    const FILE_NAME: &str = "requests.json";
+
+    async fn load() -> Result<Self, RequestError> {
+        let mut requests: Self = data_file::load(Self::FILE_NAME).await?.unwrap_or_default();
+
+        // Whatever was at the access point might still be there, but the inventory has no idea
+        for request in &mut requests.requests {
+            if let RequestState::Fetching | RequestState::Ready { .. } = request.state {
+                request.state = RequestState::Failed {
+                    error: "interrupted by a restart, check the access point".to_string(),
+                };
+            }
+        }
+
+        Ok(requests)
+    }
+
+    async fn save(&self) -> Result<(), RequestError> {
+        Ok(data_file::save(Self::FILE_NAME, self).await?)
+    }
+
+    fn push(&mut self, item: RequestedItem, amount: usize) -> RequestId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.requests.push(ItemRequest {
+            id,
+            item,
+            amount,
+            state: RequestState::Pending,
+        });
+        id
+    }
+
+    fn get(&mut self, id: RequestId) -> Result<&mut ItemRequest, RequestError> {
+        self.requests
+            .iter_mut()
+            .find(|request| request.id == id)
+            .ok_or(RequestError::NotFound(id))
+    }
+}
+
+/// Item requests made by the user, fetched to the access point one at a time by
+/// [`process_requests`]
+pub struct RequestQueue {
+    app: AppHandle,
+    requests: Mutex<Requests>,
+    changed: Notify,
+}
+
+impl RequestQueue {
+    /// Load the pending requests, `JobQueue` and `Inventory` have to be managed by `app`
+    pub async fn new(app: AppHandle) -> Self {
+        let requests = Requests::load().await.unwrap_or_else(|err| {
+            println!("Starting with no requests: {err}");
+            Requests::default()
+        });
+
+        Self {
+            app,
+            requests: Mutex::new(requests),
+            changed: Notify::new(),
+        }
+    }
+
+    pub async fn list(&self) -> Vec<ItemRequest> {
+        self.requests.lock().await.requests.clone()
+    }
+
+    /// Queue a request for `amount` of `item`, there has to be enough of it stored right now
+    pub async fn add(&self, item: RequestedItem, amount: usize) -> Result<RequestId, RequestError> {
+        self.check(&item, amount).await?;
+        self.modify(|requests| requests.push(item, amount)).await
+    }
+
+    /// Queue a request for each item and amount, they're fetched in this order unless the
+    /// requests are ordered by [`RequestOrder::ShortestTravel`]
+    pub async fn add_all(
+        &self,
+        items: Vec<(RequestedItem, usize)>,
+    ) -> Result<Vec<RequestId>, RequestError> {
+        for (item, amount) in &items {
+            self.check(item, *amount).await?;
+        }
+
+        self.modify(|requests| {
+            items
+                .into_iter()
+                .map(|(item, amount)| requests.push(item, amount))
+                .collect()
+        })
+        .await
+    }
+
+    /// Fail unless there's enough of `item` stored to take `amount`
+    async fn check(&self, item: &RequestedItem, amount: usize) -> Result<(), RequestError> {
+        if amount == 0 {
+            return Err(RequestError::NoAmount);
+        }
+        let (id, _) = self.locate(item).await?;
+        let available = self
+            .app
+            .state::<Inventory>()
+            .amount(id)
+            .await
+            .ok_or(RequestError::NoMatch)?;
+        if amount as u64 > available {
+            return Err(RequestError::NotEnough {
+                available,
+                requested: amount,
+            });
+        }
+
+        Ok(())
+    }
+
+    pub async fn set_order(&self, order: RequestOrder) -> Result<(), RequestError> {
+        self.modify(|requests| requests.order = order).await
+    }
+
+    /// The user took the requested amount, put the item back and forget the request
+    pub async fn collect(&self, id: RequestId) -> Result<(), RequestError> {
+        let request = self.requests.lock().await.get(id)?.clone();
+        let RequestState::Ready { item_id } = request.state else {
+            return Err(RequestError::NotReady(id));
+        };
+
+        let jobs = self.app.state::<JobQueue>();
+        jobs.run(Job::ReturnItem {
+            id: item_id,
+            change: QuantityChange::Take {
+                amount: request.amount,
+            },
+            remove: false,
+        })
+        .await?;
+
+        self.remove(id).await
+    }
+
+    /// Forget the request, an item already at the access point is put back untouched
+    pub async fn cancel(&self, id: RequestId) -> Result<(), RequestError> {
+        let state = self.requests.lock().await.get(id)?.state.clone();
+        match state {
+            RequestState::Fetching => return Err(RequestError::Busy(id)),
+            RequestState::Ready { item_id } => {
+                let jobs = self.app.state::<JobQueue>();
+                jobs.run(Job::ReturnItem {
+                    id: item_id,
+                    change: QuantityChange::Unchanged,
+                    remove: false,
+                })
+                .await?;
+            }
+            RequestState::Pending | RequestState::Failed { .. } => {}
+        }
+
+        self.remove(id).await
+    }
+
+    /// Queue a failed request again
+    pub async fn retry(&self, id: RequestId) -> Result<(), RequestError> {
+        self.modify(|requests| {
+            let request = requests.get(id)?;
+            let RequestState::Failed { .. } = request.state else {
+                return Err(RequestError::NotFailed(id));
+            };
+            request.state = RequestState::Pending;
+            Ok(())
+        })
+        .await?
+    }
+
+    async fn remove(&self, id: RequestId) -> Result<(), RequestError> {
+        self.modify(|requests| requests.requests.retain(|request| request.id != id))
+            .await
+    }
+
+    /// Apply `change`, then save the requests and let the frontend and [`process_requests`] know
+    ///
+    /// The change stays applied when saving fails, the requests just aren't kept across a
+    /// restart until the next successful save.
+    async fn modify<T>(&self, change: impl FnOnce(&mut Requests) -> T) -> Result<T, RequestError> {
+        let mut requests = self.requests.lock().await;
+        let result = change(&mut requests);
+
+        let saved = requests.save().await;
+        if let Err(err) = self.app.emit("requests-changed", requests.requests.clone()) {
+            println!("Failed to report the changed requests: {err}");
+        }
+        self.changed.notify_one();
+
+        saved.map(|()| result)
+    }
+
+    /// Mark the request to fetch next as fetching, none while the access point is taken or
+    /// nothing is pending
+    async fn next(&self) -> Option<(RequestId, RequestedItem)> {
+        let (pending, order, last_slot) = {
+            let requests = self.requests.lock().await;
+            // The access point only holds one item at a time
+            if requests.requests.iter().any(|request| {
+                matches!(
+                    request.state,
+                    RequestState::Fetching | RequestState::Ready { .. }
+                )
+            }) {
+                return None;
+            }
+
+            let pending = requests
+                .requests
+                .iter()
+                .filter(|request| matches!(request.state, RequestState::Pending))
+                .map(|request| (request.id, request.item.clone()))
+                .collect::<Vec<_>>();
+            (pending, requests.order, requests.last_slot)
+        };
+
+        // Locating the items waits for the database, the requests can change meanwhile
+        let (id, item) = match order {
+            RequestOrder::InOrder => pending.into_iter().next(),
+            RequestOrder::ShortestTravel => self.shortest_travel_first(pending, last_slot).await,
+        }?;
+
+        let mut claimed = false;
+        let result = self
+            .modify(|requests| {
+                if let Ok(request) = requests.get(id)
+                    && matches!(request.state, RequestState::Pending)
+                {
+                    request.state = RequestState::Fetching;
+                    claimed = true;
+                }
+            })
+            .await;
+        if let Err(err) = result {
+            println!("Failed to save the requests: {err}");
+        }
+
+        // Not claimed when it was cancelled while being located, `process_requests` tries again
+        claimed.then_some((id, item))
+    }
+
+    /// First request along the shortest path through the slots of `pending`
+    async fn shortest_travel_first(
+        &self,
+        pending: Vec<(RequestId, RequestedItem)>,
+        last_slot: Option<Rectangle>,
+    ) -> Option<(RequestId, RequestedItem)> {
+        let mut stops = Vec::with_capacity(pending.len());
+        for request in pending {
+            match self.locate(&request.1).await {
+                Ok((_, slot)) => stops.push((request, slot.pick_point())),
+                // Requests that can't be found go first so they fail right away
+                Err(_) => return Some(request),
+            }
+        }
+
+        let start = match last_slot {
+            Some(slot) => slot.pick_point(),
+            None => self.app.state::<Inventory>().access_point().await,
+        };
+        plan_order(start, stops)
+            .into_iter()
+            .next()
+            .map(|(request, _)| request)
+    }
+
+    /// Bring the requested item to the access point
+    async fn fetch(&self, id: RequestId, item: RequestedItem) {
+        let result = async {
+            let (item_id, slot) = self.locate(&item).await?;
+            let jobs = self.app.state::<JobQueue>();
+            jobs.run(Job::FetchItem { id: item_id })
+                .await
+                .map_err(|err| match err {
+                    // Removed after it was located
+                    JobError::Inventory(InventoryError::NoSuchItem(_)) => RequestError::NoMatch,
+                    err => err.into(),
+                })?;
+            Ok::<_, RequestError>((item_id, slot))
+        }
+        .await;
+
+        let result = self
+            .modify(|requests| {
+                let state = match result {
+                    Ok((item_id, slot)) => {
+                        // The item goes back to its slot once the user is done with it
+                        requests.last_slot = Some(slot);
+                        RequestState::Ready { item_id }
+                    }
+                    Err(err) => RequestState::Failed {
+                        error: err.to_string(),
+                    },
+                };
+                if let Ok(request) = requests.get(id) {
+                    request.state = state;
+                }
+            })
+            .await;
+        if let Err(err) = result {
+            println!("Failed to save the requests: {err}");
+        }
+    }
+
+    async fn locate(&self, item: &RequestedItem) -> Result<(i64, Rectangle), RequestError> {
+        let inventory = self.app.state::<Inventory>();
+        let id = match item {
+            RequestedItem::Id { id } => *id,
+            RequestedItem::Name { name } => inventory
+                .find_by_name(name)
+                .await
+                .ok_or(RequestError::NoMatch)?,
+        };
+        let slot = inventory.slot(id).await.ok_or(RequestError::NoMatch)?;
+
+        Ok((id, slot))
+    }
+}
+
+/// Fetch the pending requests one after another, runs for as long as the app does
+pub async fn process_requests(app: AppHandle) {
+    let queue = app.state::<RequestQueue>();
+
+    loop {
+        match queue.next().await {
+            Some((id, item)) => queue.fetch(id, item).await,
+            None => queue.changed.notified().await,
+        }
+    }
+}
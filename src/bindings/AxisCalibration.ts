@@ -0,0 +1,14 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Per axis calibration, maps millimetres on the plane to motor steps
+ */
+export type AxisCalibration = { steps_per_mm: number, 
+/**
+ * Play in the drive train that has to be taken up every time the axis reverses direction
+ */
+backlash_mm: number, 
+/**
+ * Set when the motor is wired so it turns the opposite way to the rest of the machine
+ */
+inverted: boolean, };
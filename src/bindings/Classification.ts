@@ -0,0 +1,10 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * A label the image might show
+ */
+export type Classification = { label: string, 
+/**
+ * Between 0 and 1
+ */
+confidence: number, };
@@ -0,0 +1,15 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Classification } from "./Classification";
+
+/**
+ * Payload of the `update-detected-object` event
+ */
+export type DetectedObject = { 
+/**
+ * The most likely label, none when the object is unknown
+ */
+label: string | null, 
+/**
+ * Every label the recognizer came up with, most likely first
+ */
+candidates: Array<Classification>, };
@@ -0,0 +1,15 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { EventKind } from "./EventKind";
+
+/**
+ * Which events to list, every field that's set has to match
+ */
+export type EventFilter = { item_id: bigint | null, item_name: string | null, kind: EventKind | null, 
+/**
+ * Only failed operations when set, only successful ones when cleared
+ */
+failed: boolean | null, 
+/**
+ * Only events at or after this time, in milliseconds since the unix epoch
+ */
+since_ms: bigint | null, };
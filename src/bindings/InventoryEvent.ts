@@ -0,0 +1,28 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { EventKind } from "./EventKind";
+import type { Rectangle } from "./Rectangle";
+
+/**
+ * Operation on an item as recorded in the history
+ */
+export type InventoryEvent = { id: bigint, 
+/**
+ * Milliseconds since the unix epoch
+ */
+timestamp_ms: bigint, kind: EventKind, 
+/**
+ * None when adding the item failed before it got one
+ */
+item_id: bigint | null, item_name: string, 
+/**
+ * Where the item was, or where it was moved to
+ */
+rect: Rectangle, 
+/**
+ * How many were added, removed, taken or restocked
+ */
+amount: bigint, 
+/**
+ * Why the operation failed, none if it went through
+ */
+error: string | null, };
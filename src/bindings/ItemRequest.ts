@@ -0,0 +1,8 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { RequestState } from "./RequestState";
+import type { RequestedItem } from "./RequestedItem";
+
+/**
+ * Payload of the `requests-changed` event, together with the other requests
+ */
+export type ItemRequest = { id: bigint, item: RequestedItem, amount: number, state: RequestState, };
@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Footprint of an item, picked by the user when adding it
+ */
+export type ItemSize = "Small" | "Medium" | "Large" | { "Custom": { width: number, height: number, } };
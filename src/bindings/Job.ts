@@ -0,0 +1,11 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Calibration } from "./Calibration";
+import type { Direction } from "./Direction";
+import type { MotionMode } from "./MotionMode";
+import type { QuantityChange } from "./QuantityChange";
+import type { Rectangle } from "./Rectangle";
+
+/**
+ * Anything that moves the machine, jobs run one at a time in the order they were submitted
+ */
+export type Job = "Homing" | "MeasurePlane" | { "MoveTo": { x: number, y: number, } } | { "MoveBy": { direction: Direction, amount: number, } } | "MoveToAccessPoint" | { "AddItem": { name: string, rect: Rectangle, amount: number, } } | { "RemoveItem": { id: bigint, } } | { "FetchItem": { id: bigint, } } | { "ReturnItem": { id: bigint, change: QuantityChange, remove: boolean, } } | "Compact" | "ExtendActuator" | "ContractActuator" | { "SetMagnet": { on: boolean, } } | { "SetCalibration": { calibration: Calibration, } } | { "SetMotionMode": { mode: MotionMode, } };
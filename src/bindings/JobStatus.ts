@@ -0,0 +1,8 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { Job } from "./Job";
+import type { JobState } from "./JobState";
+
+/**
+ * Payload of the `job-status` event, sent every time a job changes state
+ */
+export type JobStatus = { id: bigint, job: Job, state: JobState, };
@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * How [`PlaneImpl::move_with`] drives the two axes on a diagonal move
+ */
+export type MotionMode = "Independent" | "Coordinated";
@@ -0,0 +1,7 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * How [`ItemAllocator::allocate`] picks between the free rectangles an item fits in, the item
+ * always goes in the corner of the rectangle closest to home
+ */
+export type PlacementHeuristic = "BestShortSideFit" | "BestAreaFit" | "BottomLeft";
@@ -0,0 +1,7 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { PlaneDimensions } from "./PlaneDimensions";
+
+/**
+ * Sent to [`Plane::subscribe`] receivers
+ */
+export type PlaneEvent = { "PositionLost": { x: number, y: number, } } | "Rehomed" | { "DimensionsChanged": { stored: PlaneDimensions, measured: PlaneDimensions, } };
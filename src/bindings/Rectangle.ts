@@ -1,3 +1,6 @@
 // This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
 
+/**
+ * Area on the plane, in millimetres
+ */
 export type Rectangle = { x: number, y: number, width: number, height: number, };
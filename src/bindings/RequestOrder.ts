@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Order pending requests are fetched in
+ */
+export type RequestOrder = "InOrder" | "ShortestTravel";
@@ -0,0 +1,6 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Item asked for by the user
+ */
+export type RequestedItem = { "Id": { id: bigint, } } | { "Name": { name: string, } };
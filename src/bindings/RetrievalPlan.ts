@@ -0,0 +1,18 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * Order to fetch items in, each one is brought to the access point and put back in its slot
+ */
+export type RetrievalPlan = { 
+/**
+ * Item ids, in the order they're fetched
+ */
+order: Array<bigint>, 
+/**
+ * How far the head travels empty from one slot to the next, in millimetres
+ */
+empty_travel_mm: number, 
+/**
+ * Time the machine takes for the whole plan, not counting the user at the access point
+ */
+estimated_seconds: number, };
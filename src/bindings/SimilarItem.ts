@@ -0,0 +1,11 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+import type { DisplayItem } from "./DisplayItem";
+
+/**
+ * Stored item that looks like what the camera sees
+ */
+export type SimilarItem = { item: DisplayItem, 
+/**
+ * How many bits of the perceptual hashes of the photos differ, 0 when they look the same
+ */
+distance: number, };